@@ -1,18 +1,27 @@
 use axum::{
     body::Body,
-    extract::{Path, Query, State},
-    http::{header, StatusCode},
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
     response::{Html, Response, IntoResponse},
     routing::get,
     Json, Router,
 };
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::future::Future;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpListener;
 use tokio::sync::broadcast;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tokio_util::io::ReaderStream;
 use tokio_util::compat::FuturesAsyncWriteCompatExt;
 
@@ -24,347 +33,4601 @@ pub struct ServerState {
     pub is_running: bool,
 }
 
+// Keep only the most recent downloads so a long-running kiosk doesn't grow unbounded
+const RECENT_DOWNLOADS_CAP: usize = 500;
+
+// Shared text snippets are small, few, and short-lived
+const CLIPBOARD_MAX_BYTES: usize = 16 * 1024;
+const CLIPBOARD_CAP: usize = 10;
+const CLIPBOARD_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Serialize)]
+pub struct Snippet {
+    pub text: String,
+    pub timestamp: u64,
+    #[serde(skip)]
+    created: Instant,
+}
+
+#[derive(Clone, Serialize)]
+pub struct DownloadRecord {
+    pub path: String,
+    pub size: u64,
+    pub timestamp: u64,
+    pub client_ip: String,
+    // Request path the download was resolved from, which decides the share
+    // password a guest needs to see it in /api/downloads/recent
+    #[serde(skip)]
+    share_path: String,
+}
+
+/// Totals since the server started, for dashboards and /api/stats.
+#[derive(Clone, Default, Serialize)]
+pub struct TransferStats {
+    pub bytes_served: u64,
+    /// Whole files and archives sent to the end, ranged requests excluded
+    pub downloads: u64,
+    pub active_transfers: u64,
+    pub peak_concurrent_transfers: u64,
+    pub uptime_secs: u64,
+}
+
+struct TransferCounters {
+    started: Instant,
+    bytes_served: AtomicU64,
+    downloads: AtomicU64,
+    active: AtomicU64,
+    peak_active: AtomicU64,
+}
+
+const DEFAULT_SERVER_NAME: &str = "HFS Client";
+
+// Below this, gzip framing costs more than it saves
+const GZIP_MIN_SIZE: u64 = 1024;
+
+/// The server listens on all interfaces
+pub const BIND_ADDRESS: [u8; 4] = [0, 0, 0, 0];
+
+/// A shared folder or file. Clients see it under `alias` if one is set,
+/// otherwise under its base name.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "SharedFolderConfig")]
+pub struct SharedFolder {
+    pub path: String,
+    pub alias: Option<String>,
+    /// Required to browse or download anything in this share. Never sent
+    /// back out, only its hash is kept.
+    #[serde(skip)]
+    password: Option<SharePassword>,
+}
+
+// Shares can be given as a plain path or as { path, alias, password }
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SharedFolderConfig {
+    Path(String),
+    Aliased { path: String, alias: Option<String>, password: Option<String> },
+}
+
+impl From<SharedFolderConfig> for SharedFolder {
+    fn from(config: SharedFolderConfig) -> Self {
+        match config {
+            SharedFolderConfig::Path(path) => Self::from(path),
+            SharedFolderConfig::Aliased { path, alias, password } => Self {
+                path,
+                alias,
+                password: password.filter(|password| !password.is_empty()).map(|password| SharePassword::new(&password)),
+            },
+        }
+    }
+}
+
+impl From<String> for SharedFolder {
+    fn from(path: String) -> Self {
+        Self { path, alias: None, password: None }
+    }
+}
+
+impl SharedFolder {
+    pub fn is_protected(&self) -> bool {
+        self.password.is_some()
+    }
+
+    /// An empty password removes protection, like in the share config
+    pub fn set_password(&mut self, password: &str) {
+        self.password = (!password.is_empty()).then(|| SharePassword::new(password));
+    }
+}
+
+// SHA-256 of a share's password
+#[derive(Clone)]
+struct SharePassword([u8; 32]);
+
+impl SharePassword {
+    fn new(password: &str) -> Self {
+        use sha2::{Digest, Sha256};
+        Self(Sha256::digest(password.as_bytes()).into())
+    }
+
+    // Compares every byte so the time taken doesn't hint at the password
+    fn matches(&self, password: &str) -> bool {
+        let other = Self::new(password);
+        self.0.iter().zip(other.0.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+impl SharedFolder {
+    /// Whether this share is a pattern such as `~/Docs/*.pdf` rather than a
+    /// path. An existing path is taken literally even if it contains `[`.
+    pub fn is_glob(&self) -> bool {
+        self.path.contains(['*', '?', '[']) && !std::path::Path::new(&self.path).exists()
+    }
+
+    // Files and folders currently matching a glob share, each shared under
+    // its own name
+    fn glob_matches(&self) -> Vec<SharedFolder> {
+        let pattern = match self.path.strip_prefix("~/").or_else(|| self.path.strip_prefix("~\\")) {
+            Some(rest) => match std::env::var("HOME").or_else(|_| std::env::var("USERPROFILE")) {
+                Ok(home) => std::path::Path::new(&home).join(rest).to_string_lossy().into_owned(),
+                Err(_) => self.path.clone(),
+            },
+            None => self.path.clone(),
+        };
+        let Ok(paths) = glob::glob(&pattern) else {
+            return Vec::new();
+        };
+        paths
+            .flatten()
+            .filter(|path| path.is_file() || path.is_dir())
+            .map(|path| SharedFolder {
+                path: path.to_string_lossy().into_owned(),
+                alias: None,
+                password: self.password.clone(),
+            })
+            .collect()
+    }
+
+    /// The first path segment this share is served under. Aliases that are
+    /// blank or contain a slash are ignored.
+    fn name(&self) -> Option<String> {
+        let alias = self
+            .alias
+            .as_deref()
+            .map(str::trim)
+            .filter(|alias| !alias.is_empty() && !alias.contains('/') && !alias.contains('\\'));
+        match alias {
+            Some(alias) => Some(alias.to_string()),
+            None => Some(std::path::Path::new(&self.path).file_name()?.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+// Repeated browsing of the same folder skips read_dir for a few seconds,
+// which matters most on slow network drives
+const DEFAULT_LISTING_CACHE_SIZE: usize = 64;
+const DEFAULT_LISTING_CACHE_TTL_SECS: u64 = 5;
+
+const DEFAULT_ZIP_MAX_ENTRIES: usize = 100_000;
+const DEFAULT_ZIP_MAX_DEPTH: usize = 64;
+
+// Deep enough for any real folder layout, shallow enough that a nesting loop
+// ends quickly
+const DEFAULT_MAX_WALK_DEPTH: usize = 64;
+
+// Small files are read into memory a few at a time ahead of the archive
+// writer, so many tiny files on a slow disk don't stall it one by one
+const DEFAULT_ZIP_READ_AHEAD: usize = 4;
+const ZIP_READ_AHEAD_MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+// Long enough for a phone on a weak signal to get its headers through,
+// short enough that slow-loris clients can't pile up connections. Idle
+// connections cost a socket each, so they're let go sooner.
+const DEFAULT_HEADER_READ_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_KEEP_ALIVE_TIMEOUT_SECS: u64 = 15;
+
+const DEFAULT_ZIP_BUFFER_SIZE: usize = 64 * 1024;
+const ZIP_BUFFER_SIZE_RANGE: std::ops::RangeInclusive<usize> = 4 * 1024..=16 * 1024 * 1024;
+
+struct CachedListing {
+    entries: Vec<FileEntry>,
+    cached_at: Instant,
+    last_used: u64,
+}
+
+// Folder listings keyed by resolved path, evicting the least recently used
+// entry once full. Entries expire after `ttl` rather than being watched.
+struct ListingCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<PathBuf, CachedListing>,
+    tick: u64,
+}
+
+impl ListingCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self { capacity, ttl, entries: HashMap::new(), tick: 0 }
+    }
+
+    fn get(&mut self, path: &std::path::Path) -> Option<Vec<FileEntry>> {
+        self.tick += 1;
+        let listing = self.entries.get_mut(path)?;
+        if listing.cached_at.elapsed() >= self.ttl {
+            self.entries.remove(path);
+            return None;
+        }
+        listing.last_used = self.tick;
+        Some(listing.entries.clone())
+    }
+
+    fn insert(&mut self, path: PathBuf, entries: Vec<FileEntry>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&path) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, listing)| listing.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(path, CachedListing { entries, cached_at: Instant::now(), last_used: self.tick });
+    }
+}
+
+// Optional settings passed from the desktop app when starting a server
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerOptions {
+    pub server_name: Option<String>,
+    pub allowed_origins: Vec<String>,
+    /// Glob patterns matched against entry names to hide them from listings.
+    /// Defaults to hiding dotfiles; pass an empty list to show everything.
+    pub hidden_patterns: Option<Vec<String>>,
+    /// Serve the bundled web client at `/`. Defaults to true; turn it off
+    /// when a custom frontend talks to the API directly.
+    pub serve_ui: Option<bool>,
+    /// Image served at /favicon.svg instead of the built-in icon
+    pub icon_path: Option<String>,
+    /// Serve shared folders as static sites under `/site/`: a folder URL
+    /// renders its index.html and other files are returned inline with their
+    /// MIME type.
+    pub serve_index: bool,
+    /// Zips stop once they reach this many files (default 100000)
+    pub zip_max_entries: Option<usize>,
+    /// Zips stop once the files added reach this many bytes (default unlimited)
+    pub zip_max_bytes: Option<u64>,
+    /// Zips stop at folders nested deeper than this (default 64, at most
+    /// `max_walk_depth`)
+    pub zip_max_depth: Option<usize>,
+    /// No walk of a folder's contents, whether for archives, progress
+    /// estimates, totals, feeds or trees, goes deeper than this (default 64)
+    pub max_walk_depth: Option<usize>,
+    /// Small files read ahead concurrently while archiving (default 4, 0 reads
+    /// one file at a time). The archive layout doesn't depend on it.
+    pub zip_read_ahead: Option<usize>,
+    /// Bytes buffered between the archive writer and the response (default
+    /// 64 KiB). Larger buffers smooth out fast links at the cost of that much
+    /// memory per archive being sent.
+    pub zip_buffer_size: Option<usize>,
+    /// Number of folder listings kept in memory, 0 disables the cache
+    pub listing_cache_size: Option<usize>,
+    /// How long a cached listing is served before the folder is read again
+    pub listing_cache_ttl_secs: Option<u64>,
+    /// Gzip text files on the fly for clients that accept it. Ranged
+    /// requests and small or already compressed files are sent as is.
+    pub gzip_downloads: bool,
+    /// Folder the web client opens instead of the root listing, e.g.
+    /// `Photos/2024`. Must resolve to a shared folder.
+    pub default_path: Option<String>,
+    /// Format of the per-request access log
+    pub log_format: LogFormat,
+    /// File the access log is appended to. Without one, lines of either
+    /// format go to the app log.
+    pub access_log_file: Option<String>,
+    /// Requests each client IP may make per second on average. Unset
+    /// disables rate limiting. Download and archive streams don't count.
+    pub rate_limit_per_sec: Option<f64>,
+    /// Requests a client may make at once before the rate applies (default
+    /// twice the rate, at least 1)
+    pub rate_limit_burst: Option<u32>,
+    /// Headers added to every response, e.g. `["X-Content-Type-Options",
+    /// "nosniff"]`. Headers a handler sets itself are left alone.
+    pub custom_headers: Vec<(String, String)>,
+    /// HTML file shown to browsers instead of the built-in error page.
+    /// `__HFS_STATUS__`, `__HFS_MESSAGE__` and `__HFS_SERVER_NAME__` are
+    /// filled in.
+    pub error_page: Option<String>,
+    /// Downloads and archives are dropped once the client hasn't read from
+    /// them for this many seconds, releasing the file or archive task. Unset
+    /// waits forever, which paused browser downloads rely on.
+    pub stall_timeout_secs: Option<u64>,
+    /// Offer folders and selections as zip or tar archives. Defaults to
+    /// true; turn it off on weak devices to only serve single files.
+    pub allow_zip: Option<bool>,
+    /// Serve `/api/stat/*path` with timestamps, permissions and ownership.
+    /// Off by default since owners and modes say more about the machine
+    /// than listings do.
+    pub file_details: bool,
+    /// List the contents of all shared folders together at the root instead
+    /// of one folder per share. Clashing names get a ` (2)`, ` (3)`...
+    /// suffix, in share order. Password-protected shares keep their own
+    /// folder.
+    pub merge_roots: bool,
+    /// Only files with these extensions can be downloaded, previewed or
+    /// archived, e.g. `["pdf", "docx"]`. Unset allows every type.
+    pub allowed_extensions: Option<Vec<String>>,
+    /// Files with these extensions are refused even if allowed above
+    pub denied_extensions: Vec<String>,
+    /// Also leave refused files out of listings instead of only failing
+    /// their downloads
+    pub hide_blocked_files: bool,
+    /// Serve `file.br` or `file.gz` in place of `file` to clients accepting
+    /// that encoding, like nginx's brotli_static and gzip_static
+    pub precompressed: bool,
+    /// Folder with a custom web client served at `/` in place of the
+    /// built-in one. Paths the API, downloads or archives use are never
+    /// looked up there.
+    pub ui_dir: Option<String>,
+    /// Seconds a client has to send a request's headers, counted from when
+    /// it connects or its previous response ends (default 30, 0 waits
+    /// forever). Clients trickling headers in are dropped once it passes.
+    pub header_read_timeout_secs: Option<u64>,
+    /// Seconds a connection may sit idle between requests before it's
+    /// closed (default 15, 0 closes it after each response)
+    pub keep_alive_timeout_secs: Option<u64>,
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl ServerOptions {
+    fn server_name(&self) -> &str {
+        self.server_name
+            .as_deref()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .unwrap_or(DEFAULT_SERVER_NAME)
+    }
+
+    fn listing_cache(&self) -> ListingCache {
+        ListingCache::new(
+            self.listing_cache_size.unwrap_or(DEFAULT_LISTING_CACHE_SIZE),
+            Duration::from_secs(self.listing_cache_ttl_secs.unwrap_or(DEFAULT_LISTING_CACHE_TTL_SECS)),
+        )
+    }
+
+    fn zip_limits(&self) -> ZipLimits {
+        ZipLimits {
+            max_entries: self.zip_max_entries.unwrap_or(DEFAULT_ZIP_MAX_ENTRIES),
+            max_bytes: self.zip_max_bytes.unwrap_or(u64::MAX),
+            max_depth: self.zip_max_depth.unwrap_or(DEFAULT_ZIP_MAX_DEPTH).min(self.max_walk_depth()),
+        }
+    }
+
+    fn connection_timeouts(&self) -> ConnectionTimeouts {
+        let header_read = self.header_read_timeout_secs.unwrap_or(DEFAULT_HEADER_READ_TIMEOUT_SECS);
+        ConnectionTimeouts {
+            header_read: (header_read > 0).then(|| Duration::from_secs(header_read)),
+            keep_alive: Duration::from_secs(self.keep_alive_timeout_secs.unwrap_or(DEFAULT_KEEP_ALIVE_TIMEOUT_SECS)),
+        }
+    }
+
+    fn max_walk_depth(&self) -> usize {
+        self.max_walk_depth.unwrap_or(DEFAULT_MAX_WALK_DEPTH)
+    }
+
+    /// Parses `custom_headers`, naming the first one that isn't valid HTTP.
+    pub fn custom_headers(&self) -> Result<Vec<(header::HeaderName, HeaderValue)>, String> {
+        self.custom_headers
+            .iter()
+            .map(|(name, value)| {
+                let parsed_name = header::HeaderName::from_bytes(name.trim().as_bytes())
+                    .map_err(|_| format!("Invalid header name: {}", name))?;
+                let parsed_value = HeaderValue::from_str(value.trim())
+                    .map_err(|_| format!("Invalid value for header {}: {}", name, value))?;
+                Ok((parsed_name, parsed_value))
+            })
+            .collect()
+    }
+
+    /// Template of the error page, read from `error_page` if set.
+    pub fn error_page_template(&self) -> Result<String, String> {
+        match &self.error_page {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read error page {}: {}", path, e)),
+            None => Ok(ERROR_HTML.to_string()),
+        }
+    }
+
+    /// The custom web client folder, if set and it exists.
+    pub fn ui_dir(&self) -> Result<Option<PathBuf>, String> {
+        match &self.ui_dir {
+            Some(dir) if std::path::Path::new(dir).is_dir() => Ok(Some(PathBuf::from(dir))),
+            Some(dir) => Err(format!("UI folder {} does not exist", dir)),
+            None => Ok(None),
+        }
+    }
+
+    fn zip_buffer_size(&self) -> usize {
+        self.zip_buffer_size
+            .unwrap_or(DEFAULT_ZIP_BUFFER_SIZE)
+            .clamp(*ZIP_BUFFER_SIZE_RANGE.start(), *ZIP_BUFFER_SIZE_RANGE.end())
+    }
+
+    fn zip_read_ahead(&self) -> usize {
+        self.zip_read_ahead.unwrap_or(DEFAULT_ZIP_READ_AHEAD)
+    }
+
+    fn serve_ui(&self) -> bool {
+        self.serve_ui.unwrap_or(true)
+    }
+
+    fn allow_zip(&self) -> bool {
+        self.allow_zip.unwrap_or(true)
+    }
+
+    fn file_types(&self) -> FileTypes {
+        let normalize = |extensions: &[String]| -> Vec<String> {
+            extensions
+                .iter()
+                .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|ext| !ext.is_empty())
+                .collect()
+        };
+        FileTypes {
+            allowed: self.allowed_extensions.as_deref().map(normalize),
+            denied: normalize(&self.denied_extensions),
+        }
+    }
+
+    fn hidden_globs(&self) -> GlobSet {
+        let default = [".*".to_string()];
+        let patterns = self.hidden_patterns.as_deref().unwrap_or(&default);
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            match Glob::new(pattern) {
+                Ok(glob) => {
+                    builder.add(glob);
+                }
+                Err(e) => log::warn!("Ignoring invalid hidden pattern {}: {}", pattern, e),
+            }
+        }
+        builder.build().unwrap_or_else(|_| GlobSet::empty())
+    }
+}
+
+// Which files may be sent, by extension. Unlike the browse filter this is
+// enforced on every route that hands out file contents.
+#[derive(Default)]
+struct FileTypes {
+    allowed: Option<Vec<String>>,
+    denied: Vec<String>,
+}
+
+impl FileTypes {
+    // `gz` in either list also covers `backup.tar.gz`. With an allow list,
+    // files without an extension are refused.
+    fn permits(&self, name: &str) -> bool {
+        let ext = extension(name);
+        let listed = |list: &[String]| {
+            ext.as_deref().is_some_and(|ext| {
+                let last = ext.rsplit('.').next().unwrap_or(ext);
+                list.iter().any(|listed| listed == ext || listed == last)
+            })
+        };
+        self.allowed.as_deref().map_or(true, listed) && !listed(&self.denied)
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
-    pub shared_folders: Arc<Mutex<Vec<String>>>,
+    pub shared_folders: Arc<Mutex<Vec<SharedFolder>>>,
+    pub recent_downloads: Arc<Mutex<VecDeque<DownloadRecord>>>,
+    pub options: Arc<ServerOptions>,
+    pub clipboard: Arc<Mutex<VecDeque<Snippet>>>,
+    hidden: Arc<GlobSet>,
+    file_types: Arc<FileTypes>,
+    listings: Arc<Mutex<ListingCache>>,
+    folder_totals: Arc<Mutex<HashMap<PathBuf, CachedTotals>>>,
+    feeds: Arc<Mutex<HashMap<PathBuf, CachedFeed>>>,
+    checksums: Arc<Mutex<HashMap<PathBuf, CachedChecksum>>>,
+    zip_jobs: Arc<Mutex<HashMap<String, Arc<ZipJob>>>>,
+    download_limits: Arc<Mutex<HashMap<PathBuf, DownloadLimit>>>,
+    transfers: Arc<TransferCounters>,
+}
+
+// How many times a file may still be downloaded. Transfers in flight hold a
+// slot so concurrent requests can't overshoot the limit.
+struct DownloadLimit {
+    max: u32,
+    completed: u32,
+    in_flight: u32,
+}
+
+// A reserved download of a limited file, released when the response body is
+// dropped and counted only if the whole file was sent
+struct DownloadSlot {
+    limits: Arc<Mutex<HashMap<PathBuf, DownloadLimit>>>,
+    path: PathBuf,
+}
+
+impl DownloadSlot {
+    fn complete(&self) {
+        if let Some(limit) = self.limits.lock().unwrap().get_mut(&self.path) {
+            limit.completed += 1;
+        }
+    }
+}
+
+impl Drop for DownloadSlot {
+    fn drop(&mut self) {
+        if let Some(limit) = self.limits.lock().unwrap().get_mut(&self.path) {
+            limit.in_flight = limit.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+// Whether `path` has a download limit, used up or not
+fn is_download_limited(limits: &Mutex<HashMap<PathBuf, DownloadLimit>>, path: &std::path::Path) -> bool {
+    if limits.lock().unwrap().is_empty() {
+        return false;
+    }
+    path.canonicalize().is_ok_and(|path| limits.lock().unwrap().contains_key(&path))
+}
+
+impl AppState {
+    pub fn new(shared_folders: Vec<SharedFolder>, options: ServerOptions) -> Self {
+        Self {
+            shared_folders: Arc::new(Mutex::new(shared_folders)),
+            recent_downloads: Arc::new(Mutex::new(VecDeque::new())),
+            clipboard: Arc::new(Mutex::new(VecDeque::new())),
+            hidden: Arc::new(options.hidden_globs()),
+            file_types: Arc::new(options.file_types()),
+            listings: Arc::new(Mutex::new(options.listing_cache())),
+            folder_totals: Arc::new(Mutex::new(HashMap::new())),
+            feeds: Arc::new(Mutex::new(HashMap::new())),
+            checksums: Arc::new(Mutex::new(HashMap::new())),
+            options: Arc::new(options),
+            zip_jobs: Arc::new(Mutex::new(HashMap::new())),
+            download_limits: Arc::new(Mutex::new(HashMap::new())),
+            transfers: Arc::new(TransferCounters {
+                started: Instant::now(),
+                bytes_served: AtomicU64::new(0),
+                downloads: AtomicU64::new(0),
+                active: AtomicU64::new(0),
+                peak_active: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn transfer_stats(&self) -> TransferStats {
+        let counters = &self.transfers;
+        TransferStats {
+            bytes_served: counters.bytes_served.load(Ordering::Relaxed),
+            downloads: counters.downloads.load(Ordering::Relaxed),
+            active_transfers: counters.active.load(Ordering::Relaxed),
+            peak_concurrent_transfers: counters.peak_active.load(Ordering::Relaxed),
+            uptime_secs: counters.started.elapsed().as_secs(),
+        }
+    }
+
+    /// Allow a shared file to be downloaded `max` more times, or lift its
+    /// limit with `None`.
+    pub fn set_download_limit(&self, path: &std::path::Path, max: Option<u32>) -> Result<(), String> {
+        if !self.is_shared(path) || !path.is_file() {
+            return Err(format!("{} is not a shared file", path.display()));
+        }
+        let path = path.canonicalize().map_err(|e| e.to_string())?;
+        let mut limits = self.download_limits.lock().unwrap();
+        match max {
+            Some(max) => {
+                let in_flight = limits.get(&path).map_or(0, |limit| limit.in_flight);
+                limits.insert(path, DownloadLimit { max, completed: 0, in_flight });
+            }
+            None => {
+                limits.remove(&path);
+            }
+        }
+        Ok(())
+    }
+
+    // Ok(None) for files without a limit, Err once every download is used up
+    fn reserve_download(&self, path: &std::path::Path) -> Result<Option<DownloadSlot>, ApiError> {
+        let Ok(path) = path.canonicalize() else {
+            return Ok(None);
+        };
+        let mut limits = self.download_limits.lock().unwrap();
+        let Some(limit) = limits.get_mut(&path) else {
+            return Ok(None);
+        };
+        if limit.completed + limit.in_flight >= limit.max {
+            return Err(ApiError::gone("This file is no longer available for download"));
+        }
+        limit.in_flight += 1;
+        Ok(Some(DownloadSlot {
+            limits: self.download_limits.clone(),
+            path,
+        }))
+    }
+
+    // Register a zip job for progress reporting and return its id. Jobs that
+    // were never downloaded or watched are dropped after ZIP_JOB_TTL. Ids
+    // are random so one guest can't follow another's job.
+    fn start_zip_job(&self, path: String, total: u64) -> Result<String, ApiError> {
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes)
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", e.to_string()))?;
+        let id: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let mut jobs = self.zip_jobs.lock().unwrap();
+        jobs.retain(|_, job| job.created.elapsed() < ZIP_JOB_TTL);
+        jobs.insert(id.clone(), Arc::new(ZipJob::new(path, total)));
+        Ok(id)
+    }
+
+    // Archive settings shared by every archive this server writes
+    fn zip_context<'a>(&self, compression: Compression, job: Option<&'a ZipJob>, exclude: GlobSet) -> ZipContext<'a> {
+        ZipContext::new(compression, self.options.zip_limits(), job)
+            .excluding(exclude)
+            .permitting(self.file_types.clone())
+            .withholding(self.download_limits.clone())
+            .reading_ahead(self.options.zip_read_ahead())
+    }
+
+    fn zip_job(&self, id: &str) -> Option<Arc<ZipJob>> {
+        self.zip_jobs.lock().unwrap().get(id).cloned()
+    }
+
+    fn end_zip_job(&self, id: &str) {
+        self.zip_jobs.lock().unwrap().remove(id);
+    }
+
+    /// Whether `path` is a shared item or lies inside a shared folder.
+    pub fn is_shared(&self, path: &std::path::Path) -> bool {
+        let Ok(path) = path.canonicalize() else {
+            return false;
+        };
+        let folders = expand_shares(&self.shared_folders.lock().unwrap());
+        folders.iter().any(|folder| {
+            std::path::Path::new(&folder.path)
+                .canonicalize()
+                .is_ok_and(|folder| path.starts_with(folder))
+        })
+    }
+
+    // 503 when the share holding `req_path` has vanished (e.g. an unplugged
+    // drive), so clients can tell that apart from a missing file
+    fn missing(&self, req_path: &str, message: &str) -> ApiError {
+        let share = req_path.trim_matches('/').split('/').next().unwrap_or_default();
+        let folders = self.shared_folders.lock().unwrap();
+        let vanished = folders
+            .iter()
+            .any(|folder| folder.name().as_deref() == Some(share) && std::fs::metadata(&folder.path).is_err());
+        if vanished {
+            ApiError::unavailable(format!("Share {} is currently unavailable", share))
+        } else {
+            ApiError::not_found(message)
+        }
+    }
+
+    // The top level clients see, see `share_roots`
+    fn roots(&self) -> Vec<SharedFolder> {
+        share_roots(&self.shared_folders.lock().unwrap(), self.options.merge_roots, &self.hidden)
+    }
+
+    // Listings only, explicitly requested paths are still served
+    fn is_hidden(&self, name: &str) -> bool {
+        self.hidden.is_match(name)
+    }
+
+    // 403 for files of a type the server doesn't hand out
+    fn check_file_type(&self, path: &std::path::Path) -> Result<(), ApiError> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if self.file_types.permits(&name) {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden("Downloads of this file type are not allowed"))
+        }
+    }
+
+    // Files refused by check_file_type, when hide_blocked_files asks to
+    // leave them out of listings too
+    fn hides_blocked(&self, name: &str) -> bool {
+        self.options.hide_blocked_files && !self.file_types.permits(name)
+    }
+
+    // Newest first
+    pub fn recent_downloads(&self) -> Vec<DownloadRecord> {
+        self.recent_downloads.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    fn record_download(&self, path: String, share_path: String, size: u64, client: SocketAddr) {
+        let timestamp = unix_timestamp();
+        let mut log = self.recent_downloads.lock().unwrap();
+        if log.len() >= RECENT_DOWNLOADS_CAP {
+            log.pop_front();
+        }
+        log.push_back(DownloadRecord {
+            path,
+            size,
+            timestamp,
+            client_ip: client.ip().to_string(),
+            share_path,
+        });
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Binding is separate from start_server so a restart can check that the new
+// port is usable before stopping the server it replaces
+pub fn bind(port: u16) -> Result<std::net::TcpListener, String> {
+    let listener = std::net::TcpListener::bind(SocketAddr::from((BIND_ADDRESS, port)))
+        .map_err(|e| format!("Cannot listen on port {}: {}", port, e))?;
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+    Ok(listener)
+}
+
+// Every route and middleware of a server, with its state applied
+fn router(state: &AppState) -> Result<Router, String> {
+    let mut api = Router::new()
+        .route("/api/info", get(info_handler))
+        .route("/api/stats", get(stats_handler))
+        .route("/api/browse", get(browse_handler))
+        .route("/api/browse/stream", get(browse_stream_handler))
+        .route("/api/dirs", get(dirs_handler))
+        .route("/api/tree/*path", get(tree_handler))
+        .route("/api/head/*path", get(head_handler))
+        .route("/api/stat/*path", get(stat_handler))
+        .route("/api/feed/*path", get(feed_handler))
+        .route("/api/downloads/recent", get(recent_downloads_handler))
+        .route("/api/clipboard", get(get_clipboard_handler).post(post_clipboard_handler))
+        .route("/api/speedtest", get(speedtest_download_handler).post(speedtest_upload_handler));
+    if let Some(cors) = cors_layer(&state.options.allowed_origins) {
+        api = api.layer(cors);
+    }
+
+    let ui_dir = state.options.ui_dir()?.filter(|_| state.options.serve_ui());
+    let mut app = Router::new();
+    // A custom client is served by the fallback, index.html included
+    if ui_dir.is_none() {
+        let root = if state.options.serve_ui() {
+            get(root_handler)
+        } else {
+            get(api_only_handler)
+        };
+        app = app.route("/", root);
+    }
+    let mut app = app
+        .route("/favicon.svg", get(favicon_handler))
+        .merge(api)
+        .route("/download/*path", get(file_handler));
+    app = if state.options.allow_zip() {
+        app.route("/zip/folder/start", axum::routing::post(zip_start_handler))
+            .route("/zip/folder/*path", get(zip_folder_handler))
+            .route("/zip/progress/:id", get(zip_progress_handler))
+            .route("/zip/manifest/*path", get(zip_manifest_handler))
+            .route("/zip/list/*path", get(zip_list_handler))
+            .route("/zip/selection", axum::routing::post(zip_selection_handler))
+            .route("/tar/selection", axum::routing::post(tar_selection_handler))
+    } else {
+        app.route("/zip/*rest", axum::routing::any(archives_disabled_handler))
+            .route("/tar/*rest", axum::routing::any(archives_disabled_handler))
+    };
+    if state.options.serve_index {
+        app = app.route("/site/*path", get(site_handler));
+    }
+    app = match ui_dir {
+        Some(dir) => {
+            use axum::handler::HandlerWithoutStateExt;
+            let ui = tower_http::services::ServeDir::new(dir).not_found_service(fallback_handler.into_service());
+            app.fallback_service(ui)
+        }
+        None => app.fallback(fallback_handler),
+    };
+    if let Some(limiter) = RateLimiter::new(&state.options) {
+        app = app.layer(axum::middleware::from_fn_with_state(Arc::new(limiter), rate_limit_middleware));
+    }
+    let error_page = ErrorPage {
+        template: state.options.error_page_template()?,
+        server_name: escape_html(state.options.server_name()),
+    };
+    app = app.layer(axum::middleware::from_fn_with_state(Arc::new(error_page), error_page_middleware));
+    if let Some(timeout) = state.options.stall_timeout_secs.filter(|&secs| secs > 0) {
+        let timeout = Duration::from_secs(timeout);
+        app = app.layer(axum::middleware::from_fn(move |request, next| stall_timeout_middleware(timeout, request, next)));
+    }
+    let custom_headers = state.options.custom_headers()?;
+    if !custom_headers.is_empty() {
+        app = app.layer(axum::middleware::from_fn_with_state(Arc::new(custom_headers), custom_headers_middleware));
+    }
+    let access_log = Arc::new(AccessLog::open(&state.options)?);
+    Ok(app
+        .layer(axum::middleware::from_fn_with_state(access_log, access_log_middleware))
+        .with_state(state.clone()))
+}
+
+pub async fn start_server(
+    listener: std::net::TcpListener,
+    state: AppState,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) -> Result<(), String> {
+    let app = router(&state)?;
+    let listener = TcpListener::from_std(listener).map_err(|e| e.to_string())?;
+    let addr = listener.local_addr().map_err(|e| e.to_string())?;
+
+    println!("Server listening on {}", addr);
+
+    let timeouts = state.options.connection_timeouts();
+    // Connections hold a receiver each: the first channel tells them to
+    // finish up, the second is closed once they all have
+    let (closing_tx, closing_rx) = tokio::sync::watch::channel(());
+    let (closed_tx, closed_rx) = tokio::sync::watch::channel(());
+    loop {
+        let (stream, client) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if is_connection_error(&e) => continue,
+                Err(e) => {
+                    // Usually out of file descriptors, which takes a moment to clear
+                    log::warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = shutdown_rx.recv() => break,
+        };
+        let app = app.clone();
+        let closing = closing_rx.clone();
+        let closed = closed_rx.clone();
+        tokio::spawn(async move {
+            serve_connection(stream, client, app, timeouts, closing).await;
+            drop(closed);
+        });
+    }
+
+    println!("Server shutting down");
+    drop(listener);
+    closing_tx.send(()).ok();
+    drop(closed_rx);
+    closed_tx.closed().await;
+
+    // The activity log only covers the lifetime of this server
+    state.recent_downloads.lock().unwrap().clear();
+
+    Ok(())
+}
+
+fn is_connection_error(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+#[derive(Clone, Copy)]
+struct ConnectionTimeouts {
+    header_read: Option<Duration>,
+    keep_alive: Duration,
+}
+
+async fn serve_connection(
+    stream: tokio::net::TcpStream,
+    client: SocketAddr,
+    app: Router,
+    timeouts: ConnectionTimeouts,
+    mut closing: tokio::sync::watch::Receiver<()>,
+) {
+    let activity = Arc::new(ConnectionActivity::default());
+    let router = hyper_util::service::TowerToHyperService::new(app);
+    let tracked = activity.clone();
+    let service = hyper::service::service_fn(move |mut request: axum::http::Request<hyper::body::Incoming>| {
+        request.extensions_mut().insert(ConnectInfo(client));
+        let in_flight = RequestInFlight::start(tracked.clone());
+        let response = hyper::service::Service::call(&router, request);
+        async move {
+            let response = response.await?;
+            Ok::<_, std::convert::Infallible>(response.map(|body| Body::new(TrackedBody { inner: body, _in_flight: in_flight })))
+        }
+    });
+
+    // hyper's header timer also runs while waiting for the next request, so
+    // it bounds idle time too; the keep-alive watchdog only ever cuts it short
+    let connection = hyper::server::conn::http1::Builder::new()
+        .timer(hyper_util::rt::TokioTimer::new())
+        .header_read_timeout(timeouts.header_read)
+        .keep_alive(!timeouts.keep_alive.is_zero())
+        .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+        .with_upgrades();
+    tokio::pin!(connection);
+    let idle = activity.idle(timeouts.keep_alive);
+    tokio::pin!(idle);
+
+    let mut shutting_down = false;
+    loop {
+        tokio::select! {
+            result = connection.as_mut() => {
+                if let Err(e) = result {
+                    log::debug!("Connection from {} ended: {}", client, e);
+                }
+                return;
+            }
+            _ = &mut idle, if !shutting_down => {
+                connection.as_mut().graceful_shutdown();
+                shutting_down = true;
+            }
+            _ = closing.changed(), if !shutting_down => {
+                connection.as_mut().graceful_shutdown();
+                shutting_down = true;
+            }
+        }
+    }
+}
+
+// Requests being answered on one connection and when the last one ended
+#[derive(Default)]
+struct ConnectionActivity {
+    in_flight: std::sync::atomic::AtomicUsize,
+    idle_since: Mutex<Option<Instant>>,
+    // Signalled when a request ends. `notify_one` keeps the permit until
+    // `idle` waits, so an end between its check and its wait isn't lost.
+    request_ended: tokio::sync::Notify,
+}
+
+impl ConnectionActivity {
+    // Resolves once the connection has sat `keep_alive` without a request
+    // after answering at least one. Until then hyper's header timer applies.
+    async fn idle(&self, keep_alive: Duration) {
+        loop {
+            let deadline = match *self.idle_since.lock().unwrap() {
+                Some(since) if self.in_flight.load(Ordering::Relaxed) == 0 => Some(since + keep_alive),
+                _ => None,
+            };
+            match deadline {
+                Some(deadline) if deadline <= Instant::now() => return,
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => self.request_ended.notified().await,
+            }
+        }
+    }
+}
+
+struct RequestInFlight(Arc<ConnectionActivity>);
+
+impl RequestInFlight {
+    fn start(activity: Arc<ConnectionActivity>) -> Self {
+        activity.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(activity)
+    }
+}
+
+impl Drop for RequestInFlight {
+    fn drop(&mut self) {
+        *self.0.idle_since.lock().unwrap() = Some(Instant::now());
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.request_ended.notify_one();
+    }
+}
+
+// Response body that counts as an open request until it's fully sent or
+// dropped, so a long download never looks like an idle connection
+struct TrackedBody {
+    inner: Body,
+    _in_flight: RequestInFlight,
+}
+
+impl http_body::Body for TrackedBody {
+    type Data = axum::body::Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+struct AccessLog {
+    format: LogFormat,
+    file: Option<Mutex<std::fs::File>>,
+}
+
+#[derive(Serialize)]
+struct AccessLogEntry {
+    method: String,
+    path: String,
+    status: u16,
+    /// Response size, unknown for streamed bodies
+    bytes: Option<u64>,
+    duration_ms: u64,
+    client_ip: String,
+    timestamp: u64,
+}
+
+impl AccessLog {
+    fn open(options: &ServerOptions) -> Result<Self, String> {
+        let file = match &options.access_log_file {
+            Some(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("Cannot open access log {}: {}", path, e))?;
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+        Ok(Self { format: options.log_format, file })
+    }
+
+    fn write(&self, entry: &AccessLogEntry) {
+        let line = match self.format {
+            LogFormat::Json => serde_json::to_string(entry).unwrap_or_default(),
+            LogFormat::Text => format!(
+                "{} {} {} {} {} {}ms",
+                entry.client_ip,
+                entry.method,
+                entry.path,
+                entry.status,
+                entry.bytes.map_or_else(|| "-".to_string(), |bytes| bytes.to_string()),
+                entry.duration_ms,
+            ),
+        };
+        match &self.file {
+            Some(file) => {
+                use std::io::Write;
+                let line = match self.format {
+                    LogFormat::Json => line,
+                    LogFormat::Text => format!("{} {}", entry.timestamp, line),
+                };
+                if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                    log::warn!("Failed to write access log: {}", e);
+                }
+            }
+            None => log::info!("{}", line),
+        }
+    }
+}
+
+// Logs one line per request once the response headers are ready, so the
+// duration of a download covers the time to first byte only
+async fn access_log_middleware(
+    State(access_log): State<Arc<AccessLog>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let response = next.run(request).await;
+    let bytes = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .or_else(|| axum::body::HttpBody::size_hint(response.body()).exact());
+    access_log.write(&AccessLogEntry {
+        method,
+        path,
+        status: response.status().as_u16(),
+        bytes,
+        duration_ms: started.elapsed().as_millis() as u64,
+        client_ip: client.ip().to_string(),
+        timestamp: unix_timestamp(),
+    });
+    response
+}
+
+async fn fallback_handler() -> ApiError {
+    ApiError::not_found("Page not found")
+}
+
+struct ErrorPage {
+    template: String,
+    server_name: String,
+}
+
+// Error bodies are small JSON objects, anything bigger isn't one of ours
+const ERROR_BODY_MAX: usize = 64 * 1024;
+
+// Browsers navigating outside the API get an error page instead of the JSON
+// error body. The status and other headers are kept.
+async fn error_page_middleware(
+    State(page): State<Arc<ErrorPage>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let wants_page = !request.uri().path().starts_with("/api/") && prefers_html(request.headers());
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    let status = response.status();
+    if !wants_page || !is_json || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = axum::body::to_bytes(body, ERROR_BODY_MAX).await.unwrap_or_default();
+    let message = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|value| value["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| status.canonical_reason().unwrap_or("Error").to_string());
+    let html = page
+        .template
+        .replace("__HFS_STATUS__", status.as_str())
+        .replace("__HFS_MESSAGE__", &escape_html(&message))
+        .replace("__HFS_SERVER_NAME__", &page.server_name);
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(html))
+}
+
+async fn custom_headers_middleware(
+    State(custom_headers): State<Arc<Vec<(header::HeaderName, HeaderValue)>>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    for (name, value) in custom_headers.iter() {
+        if !headers.contains_key(name) {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+    response
+}
+
+// Buckets of clients idle long enough to be full again are dropped once
+// this many are tracked
+const RATE_LIMIT_MAX_CLIENTS: usize = 4096;
+
+// Token bucket per client IP: each request takes a token, and tokens come
+// back at `rate` per second up to `burst`
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<std::net::IpAddr, TokenBucket>>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new(options: &ServerOptions) -> Option<Self> {
+        let rate = options.rate_limit_per_sec.filter(|rate| *rate > 0.0)?;
+        let burst = match options.rate_limit_burst {
+            Some(burst) => f64::from(burst.max(1)),
+            None => (rate * 2.0).max(1.0),
+        };
+        Some(Self { rate, burst, buckets: Mutex::new(HashMap::new()) })
+    }
+
+    // Takes a token for `ip`, or returns how long until one is available
+    fn check(&self, ip: std::net::IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= RATE_LIMIT_MAX_CLIENTS {
+            let refill = Duration::from_secs_f64(self.burst / self.rate);
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < refill);
+        }
+        let bucket = buckets.entry(ip).or_insert(TokenBucket { tokens: self.burst, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+// Downloads and archives are long-lived streams, so a request for one
+// doesn't say much about load and isn't counted
+fn is_download_stream(path: &str) -> bool {
+    path.starts_with("/download/")
+        || path.starts_with("/zip/folder/")
+        || path == "/zip/selection"
+        || path == "/tar/selection"
+}
+
+async fn stall_timeout_middleware(
+    timeout: Duration,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let stream = is_download_stream(request.uri().path());
+    let response = next.run(request).await;
+    if !stream {
+        return response;
+    }
+    response.map(|body| Body::new(StallGuard::new(body, timeout)))
+}
+
+// Response body that is dropped, with the file or archive task behind it,
+// once the client stops reading. Time spent waiting for our own data, e.g. a
+// slow disk or a large file being compressed, doesn't count as a stall.
+struct StallGuard {
+    inner: Arc<Mutex<Option<Body>>>,
+    activity: Arc<StallActivity>,
+}
+
+struct StallActivity {
+    started: Instant,
+    // Milliseconds after `started` of the last poll by the client
+    last_poll_ms: AtomicU64,
+    waiting_for_data: AtomicBool,
+}
+
+impl StallGuard {
+    fn new(body: Body, timeout: Duration) -> Self {
+        let inner = Arc::new(Mutex::new(Some(body)));
+        let activity = Arc::new(StallActivity {
+            started: Instant::now(),
+            last_poll_ms: AtomicU64::new(0),
+            waiting_for_data: AtomicBool::new(false),
+        });
+
+        // The watchdog ends on its own once the response is done and dropped
+        let slot = Arc::downgrade(&inner);
+        let watched = activity.clone();
+        tokio::spawn(async move {
+            let mut wait = timeout;
+            loop {
+                tokio::time::sleep(wait).await;
+                let Some(slot) = slot.upgrade() else {
+                    return;
+                };
+                let last_poll = Duration::from_millis(watched.last_poll_ms.load(Ordering::Relaxed));
+                let idle = watched.started.elapsed().saturating_sub(last_poll);
+                if idle >= timeout && !watched.waiting_for_data.load(Ordering::Relaxed) {
+                    log::info!("Dropping download stalled for {}s", idle.as_secs());
+                    slot.lock().unwrap().take();
+                    return;
+                }
+                wait = timeout.saturating_sub(idle).max(Duration::from_secs(1));
+            }
+        });
+
+        Self { inner, activity }
+    }
+}
+
+impl http_body::Body for StallGuard {
+    type Data = axum::body::Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        let activity = &self.activity;
+        activity
+            .last_poll_ms
+            .store(activity.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        let mut inner = self.inner.lock().unwrap();
+        let Some(body) = inner.as_mut() else {
+            return Poll::Ready(Some(Err(axum::Error::new("Download stalled"))));
+        };
+        let frame = Pin::new(body).poll_frame(cx);
+        activity.waiting_for_data.store(frame.is_pending(), Ordering::Relaxed);
+        frame
+    }
+}
+
+async fn rate_limit_middleware(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    if is_download_stream(request.uri().path()) {
+        return next.run(request).await;
+    }
+    match limiter.check(client.ip()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let mut response = ApiError::too_many_requests("Too many requests, slow down").into_response();
+            // Retry-After is in whole seconds, round up so the retry succeeds
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs.max(1)));
+            response
+        }
+    }
+}
+
+// Cross-origin access to the API is opt-in. With no origins configured the
+// browser's same-origin policy applies as before.
+fn cors_layer(allowed_origins: &[String]) -> Option<CorsLayer> {
+    if allowed_origins.is_empty() {
+        return None;
+    }
+    let layer = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::CONTENT_TYPE]);
+
+    // Credentials can't be combined with a wildcard origin
+    if allowed_origins.iter().any(|origin| origin == "*") {
+        return Some(layer.allow_origin(AllowOrigin::any()));
+    }
+    let origins: Vec<HeaderValue> = allowed_origins
+        .iter()
+        .filter_map(|origin| match HeaderValue::from_str(origin.trim_end_matches('/')) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                log::warn!("Ignoring invalid CORS origin: {}", origin);
+                None
+            }
+        })
+        .collect();
+    Some(
+        layer
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_credentials(true),
+    )
+}
+
+// Wraps a response body reader and calls `on_complete` with the number of
+// bytes read once it hits EOF, i.e. the whole body has been handed to the client.
+struct TrackedReader<R> {
+    inner: R,
+    bytes: u64,
+    on_complete: Option<Box<dyn FnOnce(u64) + Send>>,
+}
+
+impl<R> TrackedReader<R> {
+    fn new(inner: R, on_complete: impl FnOnce(u64) + Send + 'static) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            on_complete: Some(Box::new(on_complete)),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TrackedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = buf.filled().len() - before;
+            self.bytes += read as u64;
+            if read == 0 && buf.remaining() > 0 {
+                if let Some(on_complete) = self.on_complete.take() {
+                    on_complete(self.bytes);
+                }
+            }
+        }
+        result
+    }
+}
+
+// Feeds a response body into the transfer stats. The transfer counts as
+// active until the body is dropped, and as a download once a `whole` body
+// reaches its end.
+struct CountedReader<R> {
+    inner: R,
+    counters: Arc<TransferCounters>,
+    whole: bool,
+}
+
+impl<R> CountedReader<R> {
+    fn new(inner: R, state: &AppState, whole: bool) -> Self {
+        let counters = state.transfers.clone();
+        let active = counters.active.fetch_add(1, Ordering::Relaxed) + 1;
+        counters.peak_active.fetch_max(active, Ordering::Relaxed);
+        Self { inner, counters, whole }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = buf.filled().len() - before;
+            self.counters.bytes_served.fetch_add(read as u64, Ordering::Relaxed);
+            if read == 0 && buf.remaining() > 0 && self.whole {
+                self.whole = false;
+                self.counters.downloads.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+impl<R> Drop for CountedReader<R> {
+    fn drop(&mut self) {
+        self.counters.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Adds the bytes read to a zip job's progress counter
+struct ProgressReader<'a, R> {
+    inner: R,
+    job: Option<&'a ZipJob>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Some(job) = self.job {
+            job.processed.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+// Paces reads so the body is delivered at no more than `bytes_per_sec`.
+// A `None` rate passes reads straight through.
+struct ThrottledReader<R> {
+    inner: R,
+    bytes_per_sec: Option<u64>,
+    started: Instant,
+    sent: u64,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<R> ThrottledReader<R> {
+    fn new(inner: R, bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            inner,
+            bytes_per_sec: bytes_per_sec.filter(|rate| *rate > 0),
+            started: Instant::now(),
+            sent: 0,
+            sleep: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if let Some(rate) = self.bytes_per_sec {
+            loop {
+                if let Some(sleep) = self.sleep.as_mut() {
+                    ready!(sleep.as_mut().poll(cx));
+                    self.sleep = None;
+                }
+                // How far ahead of the allowed rate we are, in seconds
+                let ahead = self.sent as f64 / rate as f64 - self.started.elapsed().as_secs_f64();
+                if ahead <= 0.0 {
+                    break;
+                }
+                self.sleep = Some(Box::pin(tokio::time::sleep(Duration::from_secs_f64(ahead))));
+            }
+        }
+
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.sent += (buf.filled().len() - before) as u64;
+        result
+    }
+}
+
+// Newest first, expired snippets are dropped on access
+fn live_snippets(clipboard: &mut VecDeque<Snippet>) -> Vec<Snippet> {
+    clipboard.retain(|snippet| snippet.created.elapsed() < CLIPBOARD_TTL);
+    clipboard.iter().rev().cloned().collect()
+}
+
+async fn get_clipboard_handler(State(state): State<AppState>) -> Json<Vec<Snippet>> {
+    let mut clipboard = state.clipboard.lock().unwrap();
+    Json(live_snippets(&mut clipboard))
+}
+
+#[derive(Deserialize)]
+struct SnippetRequest {
+    text: String,
+}
+
+async fn post_clipboard_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<SnippetRequest>,
+) -> Result<Json<Vec<Snippet>>, ApiError> {
+    if payload.text.trim().is_empty() {
+        return Err(ApiError::bad_request("Snippet is empty"));
+    }
+    if payload.text.len() > CLIPBOARD_MAX_BYTES {
+        return Err(ApiError::payload_too_large(format!("Snippet exceeds {} bytes", CLIPBOARD_MAX_BYTES)));
+    }
+
+    let mut clipboard = state.clipboard.lock().unwrap();
+    if clipboard.len() >= CLIPBOARD_CAP {
+        clipboard.pop_front();
+    }
+    clipboard.push_back(Snippet {
+        text: payload.text,
+        timestamp: unix_timestamp(),
+        created: Instant::now(),
+    });
+    Ok(Json(live_snippets(&mut clipboard)))
+}
+
+// Speed tests send and take generated data, so they measure the network
+// rather than the disk. The cap keeps a client from tying up the link.
+const SPEEDTEST_DEFAULT_BYTES: u64 = 10 * 1024 * 1024;
+const SPEEDTEST_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct SpeedtestQuery {
+    bytes: Option<u64>,
+}
+
+// `bytes` zero bytes (default 10 MiB), for the client to time
+async fn speedtest_download_handler(Query(query): Query<SpeedtestQuery>) -> Result<Response, ApiError> {
+    let bytes = query.bytes.unwrap_or(SPEEDTEST_DEFAULT_BYTES);
+    if bytes > SPEEDTEST_MAX_BYTES {
+        return Err(ApiError::bad_request(format!("bytes must be at most {}", SPEEDTEST_MAX_BYTES)));
+    }
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, bytes)
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from_stream(ReaderStream::new(tokio::io::repeat(0).take(bytes))))
+        .unwrap())
+}
+
+#[derive(Serialize)]
+struct SpeedtestResult {
+    bytes: u64,
+    duration_ms: u64,
+    /// Megabits per second, as link speeds are usually quoted
+    mbps: f64,
+}
+
+// Reads and discards the request body, timing it from the moment the
+// headers arrived
+async fn speedtest_upload_handler(body: Body) -> Result<Json<SpeedtestResult>, ApiError> {
+    use http_body::Body as _;
+    let started = Instant::now();
+    let mut body = body;
+    let mut bytes = 0;
+    while let Some(frame) = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+        let frame = frame.map_err(|e| ApiError::bad_request(e.to_string()))?;
+        bytes += frame.data_ref().map_or(0, |data| data.len() as u64);
+        if bytes > SPEEDTEST_MAX_BYTES {
+            return Err(ApiError::payload_too_large(format!("Speed tests are limited to {} bytes", SPEEDTEST_MAX_BYTES)));
+        }
+    }
+    let elapsed = started.elapsed();
+    Ok(Json(SpeedtestResult {
+        bytes,
+        duration_ms: elapsed.as_millis() as u64,
+        mbps: bytes as f64 * 8.0 / 1_000_000.0 / elapsed.as_secs_f64().max(0.001),
+    }))
+}
+
+#[derive(Serialize)]
+struct ShareSpace {
+    name: String,
+    free_bytes: Option<u64>,
+    total_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ServerStats {
+    shares: Vec<ShareSpace>,
+    transfers: TransferStats,
+}
+
+// Free/total space of the filesystem backing each share, so clients can warn
+// before a disk fills up, plus the transfer totals for remote monitoring
+async fn stats_handler(State(state): State<AppState>) -> Json<ServerStats> {
+    let folders = state.shared_folders.lock().unwrap().clone();
+    let shares = tokio::task::spawn_blocking(move || {
+        folders
+            .iter()
+            .filter_map(|folder| {
+                let path = PathBuf::from(&folder.path);
+                let name = folder.name()?;
+                // Single-file shares report the space of their folder
+                let dir = if path.is_dir() { path.as_path() } else { path.parent()? };
+                Some(ShareSpace {
+                    name,
+                    free_bytes: fs2::available_space(dir).ok(),
+                    total_bytes: fs2::total_space(dir).ok(),
+                })
+            })
+            .collect()
+    })
+    .await
+    .unwrap_or_default();
+
+    Json(ServerStats { shares, transfers: state.transfer_stats() })
+}
+
+// Public server settings, served from /api/info and injected into the web UI
+#[derive(Serialize)]
+struct ServerInfo {
+    server_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_path: Option<String>,
+    /// False when folders can't be downloaded as archives
+    allow_zip: bool,
+    /// Shares as configured, before globs are expanded. 0 tells the client
+    /// the root is empty because nothing is shared.
+    share_count: usize,
+}
+
+impl AppState {
+    fn server_info(&self) -> ServerInfo {
+        ServerInfo {
+            server_name: self.options.server_name().to_string(),
+            default_path: self.options.default_path.as_deref().map(|path| path.trim_matches('/').to_string()),
+            allow_zip: self.options.allow_zip(),
+            share_count: self.shared_folders.lock().unwrap().len(),
+        }
+    }
+}
+
+async fn info_handler(State(state): State<AppState>) -> Json<ServerInfo> {
+    Json(state.server_info())
+}
+
+// Downloads from protected shares are only listed for guests who unlocked them
+async fn recent_downloads_handler(State(state): State<AppState>, access: ShareAccess) -> Json<Vec<DownloadRecord>> {
+    let roots = state.roots();
+    let mut downloads = state.recent_downloads();
+    downloads.retain(|record| access.resolve(&roots, &record.share_path).is_ok());
+    Json(downloads)
+}
+
+// Uniform JSON error body: `{ "error": { "code", "message" } }`
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self { status, code, message: message.into() }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    fn payload_too_large(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", message)
+    }
+
+    fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, "unavailable", message)
+    }
+
+    fn gone(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::GONE, "gone", message)
+    }
+
+    fn password_required(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "password_required", message)
+    }
+
+    fn too_many_requests(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::TOO_MANY_REQUESTS, "too_many_requests", message)
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => Self::not_found(e.to_string()),
+            std::io::ErrorKind::PermissionDenied => Self::forbidden(e.to_string()),
+            _ => Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", e.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    code: &'a str,
+    message: &'a str,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: ErrorDetail { code: self.code, message: &self.message },
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct InvalidFolder {
+    pub path: String,
+    pub reason: String,
+}
+
+// Split shared items into the ones that can be served and the ones that can't.
+// Items may be folders or single files, anything else is rejected.
+pub async fn validate_shared_folders(
+    shared_folders: Vec<SharedFolder>,
+) -> (Vec<SharedFolder>, Vec<InvalidFolder>) {
+    let mut valid = Vec::new();
+    let mut invalid = Vec::new();
+    for folder in shared_folders {
+        // Patterns are expanded per request, matching nothing yet is fine
+        if folder.is_glob() {
+            match glob::Pattern::new(&folder.path) {
+                Ok(_) => valid.push(folder),
+                Err(e) => invalid.push(InvalidFolder { path: folder.path, reason: format!("Invalid pattern: {}", e) }),
+            }
+            continue;
+        }
+        let reason = match tokio::fs::metadata(&folder.path).await {
+            Ok(meta) if meta.is_dir() || meta.is_file() => None,
+            Ok(_) => Some("Not a folder or regular file".to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        match reason {
+            Some(reason) => invalid.push(InvalidFolder { path: folder.path, reason }),
+            None => valid.push(folder),
+        }
+    }
+    (valid, invalid)
+}
+
+// Shared items with glob shares replaced by what they match right now, so
+// files outside the pattern are neither listed nor served
+fn expand_shares(shared_items: &[SharedFolder]) -> Vec<SharedFolder> {
+    let mut expanded = Vec::new();
+    for item in shared_items {
+        if item.is_glob() {
+            expanded.extend(item.glob_matches());
+        } else {
+            expanded.push(item.clone());
+        }
+    }
+    expanded
+}
+
+// Every share under its own name, with globs expanded. With `merge` the
+// visible entries of shared folders take their place, each standing in as a
+// share of its own. Protected folders stay a single locked entry, so their
+// contents aren't listed at the root before the password is given.
+fn share_roots(shared_items: &[SharedFolder], merge: bool, hidden: &GlobSet) -> Vec<SharedFolder> {
+    let shares = expand_shares(shared_items);
+    if !merge {
+        return shares;
+    }
+    let mut roots = Vec::new();
+    let mut taken = HashSet::new();
+    for share in shares {
+        let is_dir = std::path::Path::new(&share.path).is_dir();
+        if !is_dir || share.password.is_some() {
+            if let Some(name) = share.name() {
+                let alias = unique_name(&name, is_dir, &mut taken);
+                roots.push(SharedFolder { alias: Some(alias), ..share });
+            }
+            continue;
+        }
+        let Ok(read) = std::fs::read_dir(&share.path) else {
+            continue;
+        };
+        let mut children: Vec<_> = read
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                !hidden.is_match(name.as_ref()) && !is_special_file(path)
+            })
+            .collect();
+        children.sort();
+        for child in children {
+            let name = child.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            let alias = unique_name(&name, child.is_dir(), &mut taken);
+            roots.push(SharedFolder {
+                path: child.to_string_lossy().into_owned(),
+                alias: Some(alias),
+                password: None,
+            });
+        }
+    }
+    roots
+}
+
+// `name`, or `name (2)`, `name (3)`... when taken. File suffixes go before
+// the extension so the file still opens with the right app.
+fn unique_name(name: &str, is_dir: bool, taken: &mut HashSet<String>) -> String {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 && !is_dir => name.split_at(dot),
+        _ => (name, ""),
+    };
+    let mut candidate = name.to_string();
+    let mut n = 2;
+    while !taken.insert(candidate.clone()) {
+        candidate = format!("{} ({}){}", stem, n, extension);
+        n += 1;
+    }
+    candidate
+}
+
+/// Checks that `default_path` names a folder inside one of the shares.
+pub fn validate_default_path(
+    shared_folders: &[SharedFolder],
+    options: &ServerOptions,
+    default_path: &str,
+) -> Result<(), String> {
+    if default_path.contains("..") {
+        return Err(format!("Invalid default path: {}", default_path));
+    }
+    let roots = share_roots(shared_folders, options.merge_roots, &options.hidden_globs());
+    match resolve_path(&roots, default_path) {
+        Some(path) if path.is_dir() => Ok(()),
+        _ => Err(format!("Default path {} is not a shared folder", default_path)),
+    }
+}
+
+// Helper to resolve a relative URL path to a real file path based on the
+// share roots
+fn resolve_path(shared_items: &[SharedFolder], relative_path: &str) -> Option<PathBuf> {
+    matching_share(shared_items, relative_path).map(|(_, path)| path)
+}
+
+// The share a relative URL path falls in, with the real path it points to
+fn matching_share(shared_items: &[SharedFolder], relative_path: &str) -> Option<(SharedFolder, PathBuf)> {
+    let relative_path = relative_path.trim_matches('/');
+    if relative_path.is_empty() { return None; }
+
+    // Iterate over shared items
+    for item in shared_items.iter().cloned() {
+        let item_path = PathBuf::from(&item.path);
+        let item_name = item.name()?;
+        
+        if relative_path == item_name {
+            return Some((item, item_path));
+        } else if relative_path.starts_with(&format!("{}/", item_name)) {
+            let rest = &relative_path[item_name.len() + 1..];
+            return Some((item, item_path.join(rest)));
+        }
+    }
+    None
+}
+
+// The password a request offers for protected shares: the X-Share-Password
+// header, or a `share_password` query parameter for plain links such as
+// downloads where a browser can't add headers
+#[derive(Clone, Default)]
+struct ShareAccess {
+    password: Option<String>,
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for ShareAccess {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let header = parts
+            .headers
+            .get("x-share-password")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let password = header.or_else(|| {
+            let Query(mut query) = Query::<HashMap<String, String>>::try_from_uri(&parts.uri).ok()?;
+            query.remove("share_password")
+        });
+        Ok(Self { password })
+    }
+}
+
+impl ShareAccess {
+    // resolve_path for a request: 401 when the path is inside a share whose
+    // password wasn't given, scoped to that share so others stay open
+    fn resolve(&self, shared_items: &[SharedFolder], relative_path: &str) -> Result<Option<PathBuf>, ApiError> {
+        let Some((share, path)) = matching_share(shared_items, relative_path) else {
+            return Ok(None);
+        };
+        let Some(expected) = &share.password else {
+            return Ok(Some(path));
+        };
+        let name = share.name().unwrap_or_default();
+        match &self.password {
+            Some(password) if expected.matches(password) => Ok(Some(path)),
+            Some(_) => Err(ApiError::password_required(format!("Wrong password for {}", name))),
+            None => Err(ApiError::password_required(format!("{} is password protected", name))),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct FileEntry {
+    name: String,
+    path: String,
+    is_dir: bool,
+    size: Option<u64>,
+    kind: &'static str,
+    /// Last modification time in seconds since the Unix epoch
+    modified: Option<u64>,
+    /// Set on shared roots only, false while the share can't be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available: Option<bool>,
+    /// Files inside a shared root, with `size` holding their total. Only
+    /// filled in when the root listing is requested with `totals=1`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_count: Option<u64>,
+    /// Set when the walk hit the entry or depth limit, so the totals are
+    /// lower bounds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    totals_truncated: Option<bool>,
+    /// Set on shared roots that need a password
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protected: Option<bool>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct FolderTotals {
+    file_count: u64,
+    size: u64,
+    truncated: bool,
+}
+
+struct CachedTotals {
+    modified: Option<SystemTime>,
+    totals: FolderTotals,
+}
+
+fn modified_secs(meta: &std::fs::Metadata) -> Option<u64> {
+    meta.modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
+
+// Compound extensions that name a different type than their last part
+const COMPOUND_EXTENSIONS: &[&str] = &["tar.gz", "tar.bz2", "tar.xz", "tar.zst"];
+
+// Lowercased extension of a file name, e.g. `jpg` for `Photo.JPG` and
+// `tar.gz` for `backup.TAR.GZ`. Dotfiles such as `.bashrc` have none.
+fn extension(name: &str) -> Option<String> {
+    let name = name.to_ascii_lowercase();
+    let (stem, ext) = name.rsplit_once('.')?;
+    if stem.is_empty() {
+        return None;
+    }
+    let compound = COMPOUND_EXTENSIONS
+        .iter()
+        .find(|compound| name.ends_with(&format!(".{}", compound)) && name.len() > compound.len() + 1);
+    Some(compound.map_or(ext, |compound| *compound).to_string())
+}
+
+// Classify a file by extension so the client can pick an icon. Folders are
+// always "other"; `is_dir` stays the source of truth for them.
+fn file_kind(name: &str, is_dir: bool) -> &'static str {
+    if is_dir {
+        return "other";
+    }
+    let Some(ext) = extension(name) else {
+        return "other";
+    };
+    match ext.as_str() {
+        "jpg" | "jpeg" | "png" | "gif" | "webp" | "svg" | "bmp" | "ico" | "heic" | "tiff" => "image",
+        "mp4" | "mkv" | "mov" | "avi" | "webm" | "m4v" | "wmv" | "flv" => "video",
+        "mp3" | "wav" | "flac" | "aac" | "ogg" | "m4a" | "opus" | "wma" => "audio",
+        "zip" | "tar" | "gz" | "tgz" | "bz2" | "xz" | "7z" | "rar" | "zst" | "tar.gz" | "tar.bz2" | "tar.xz" | "tar.zst" => "archive",
+        "pdf" | "doc" | "docx" | "xls" | "xlsx" | "ppt" | "pptx" | "odt" | "ods" | "odp" | "txt" | "md" | "rtf" | "csv" | "epub" => "document",
+        "rs" | "js" | "ts" | "py" | "go" | "c" | "h" | "cpp" | "hpp" | "java" | "kt" | "swift" | "rb" | "php" | "sh" | "html" | "css" | "json" | "toml" | "yaml" | "yml" | "xml" | "vue" => "code",
+        _ => "other",
+    }
+}
+
+// Enough for the magic numbers `infer` knows about
+const SNIFF_BYTES: usize = 512;
+
+// Guesses the type of a file the extension says nothing about, e.g. a
+// PNG without one, from its first bytes. Leaves the file at the start.
+async fn sniff_content_type(file: &mut File) -> std::io::Result<Option<&'static str>> {
+    let mut head = Vec::with_capacity(SNIFF_BYTES);
+    (&mut *file).take(SNIFF_BYTES as u64).read_to_end(&mut head).await?;
+    file.rewind().await?;
+    Ok(infer::get(&head).map(|kind| kind.mime_type()))
+}
+
+fn content_type(path: &std::path::Path) -> &'static str {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let ext = extension(&name).unwrap_or_default();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "md" | "log" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "ico" => "image/x-icon",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mkv" => "video/x-matroska",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" | "opus" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        "zip" => "application/zip",
+        "tar" => "application/x-tar",
+        "gz" | "tgz" | "tar.gz" => "application/gzip",
+        "bz2" | "tar.bz2" => "application/x-bzip2",
+        "xz" | "tar.xz" => "application/x-xz",
+        "zst" | "tar.zst" => "application/zstd",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize)]
+struct BrowseQuery {
+    path: Option<String>,
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+    /// Comma-separated kinds (`image`, `video`, ...) and/or extensions to show
+    filter: Option<String>,
+    /// Skip the listing cache, e.g. `nocache=1` after changing files on disk
+    nocache: Option<String>,
+    /// Count the files and bytes inside each shared root, e.g. `totals=1`.
+    /// Off by default since it walks every share.
+    totals: Option<String>,
+    /// Page size. With `limit` or `cursor` set the response is a `BrowsePage`
+    /// instead of a plain array.
+    limit: Option<usize>,
+    /// `next_cursor` of the previous page. Only valid with the same `sort`
+    /// and `order` it was issued for.
+    cursor: Option<String>,
+    /// Indent the JSON for reading, e.g. `pretty=1` with curl
+    pretty: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 200;
+
+#[derive(Serialize)]
+struct BrowsePage {
+    entries: Vec<FileEntry>,
+    /// Continues after the last entry of this page, `None` on the last page
+    next_cursor: Option<String>,
+}
+
+// Position in a sorted listing. Pages continue after the last entry's sort
+// key rather than an offset, so files added or removed while scrolling
+// don't make entries repeat or go missing.
+#[derive(Serialize, Deserialize)]
+struct PageCursor {
+    sort: SortKey,
+    order: SortOrder,
+    is_dir: bool,
+    name: String,
+    size: Option<u64>,
+    modified: Option<u64>,
+}
+
+impl PageCursor {
+    fn after(entry: &FileEntry, sort: SortKey, order: SortOrder) -> Self {
+        Self {
+            sort,
+            order,
+            is_dir: entry.is_dir,
+            name: entry.name.clone(),
+            size: entry.size,
+            modified: entry.modified,
+        }
+    }
+
+    // Hex of the JSON, opaque to clients and safe in a query string
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        json.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        if cursor.len() % 2 != 0 {
+            return None;
+        }
+        let json = (0..cursor.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    // Whether `entry` sorts after the cursor
+    fn is_before(&self, entry: &FileEntry) -> bool {
+        let position = SortPosition {
+            is_dir: self.is_dir,
+            name: &self.name,
+            size: self.size,
+            modified: self.modified,
+        };
+        compare_positions(&position, &entry.position(), self.sort, self.order).is_lt()
+    }
+}
+
+// The fields a listing is sorted by
+struct SortPosition<'a> {
+    is_dir: bool,
+    name: &'a str,
+    size: Option<u64>,
+    modified: Option<u64>,
+}
+
+impl FileEntry {
+    fn position(&self) -> SortPosition<'_> {
+        SortPosition { is_dir: self.is_dir, name: &self.name, size: self.size, modified: self.modified }
+    }
+}
+
+impl BrowseQuery {
+    fn nocache(&self) -> bool {
+        is_enabled(self.nocache.as_deref())
+    }
+
+    fn totals(&self) -> bool {
+        is_enabled(self.totals.as_deref())
+    }
+
+    fn pretty(&self) -> bool {
+        is_enabled(self.pretty.as_deref())
+    }
+
+    fn filter(&self) -> EntryFilter {
+        EntryFilter::parse(self.filter.as_deref().unwrap_or(""))
+    }
+}
+
+fn is_enabled(flag: Option<&str>) -> bool {
+    flag.is_some_and(|value| value != "0" && value != "false")
+}
+
+// Limits a listing to certain file kinds or extensions. Directories always
+// pass so the client can still navigate into them.
+struct EntryFilter(Vec<String>);
+
+impl EntryFilter {
+    fn parse(filter: &str) -> Self {
+        Self(
+            filter
+                .split(',')
+                .map(|token| token.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|token| !token.is_empty())
+                .collect(),
+        )
+    }
+
+    fn matches(&self, entry: &FileEntry) -> bool {
+        if self.0.is_empty() || entry.is_dir {
+            return true;
+        }
+        // `gz` still matches `backup.tar.gz`, `tar.gz` only the compound one
+        let ext = extension(&entry.name);
+        self.0.iter().any(|token| {
+            token == entry.kind
+                || ext.as_ref().is_some_and(|ext| {
+                    ext == token || ext.ends_with(&format!(".{}", token))
+                })
+        })
+    }
+}
+
+// Directories always come first, the sort key and order apply within each group
+fn sort_entries(entries: &mut [FileEntry], sort: SortKey, order: SortOrder) {
+    entries.sort_by(|a, b| compare_positions(&a.position(), &b.position(), sort, order));
+}
+
+fn compare_positions(a: &SortPosition, b: &SortPosition, sort: SortKey, order: SortOrder) -> std::cmp::Ordering {
+    if a.is_dir != b.is_dir {
+        return b.is_dir.cmp(&a.is_dir);
+    }
+    let ordering = match sort {
+        SortKey::Name => a.name.cmp(b.name),
+        SortKey::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(b.name)),
+        SortKey::Modified => a.modified.cmp(&b.modified).then_with(|| a.name.cmp(b.name)),
+    };
+    match order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+    }
+}
+
+// Root of the listing: one entry per shared item
+fn root_entries(state: &AppState) -> Vec<FileEntry> {
+    // We hold lock only here, no awaits
+    let folders = state.roots();
+    let mut entries = Vec::new();
+    for folder in folders.iter() {
+        let path = PathBuf::from(&folder.path);
+        if let Some(name_str) = folder.name() {
+            let meta = path.metadata().ok();
+            let is_dir = meta.as_ref().map(|m| m.is_dir()).unwrap_or(false);
+            if !is_dir && state.hides_blocked(&name_str) {
+                continue;
+            }
+            entries.push(FileEntry {
+                kind: file_kind(&name_str, is_dir),
+                name: name_str.clone(),
+                path: name_str,
+                is_dir,
+                size: meta.as_ref().filter(|m| m.is_file()).map(|m| m.len()),
+                modified: meta.as_ref().and_then(modified_secs),
+                available: Some(meta.is_some()),
+                file_count: None,
+                totals_truncated: None,
+                protected: folder.password.is_some().then_some(true),
+            });
+        }
+    }
+    entries
+}
+
+// FIFOs, sockets and device nodes can block or never end when read, so they
+// are left out of listings and archives and refused for download
+fn is_special_file(path: &std::path::Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|meta| !meta.is_file() && !meta.is_dir())
+}
+
+// Listing entry for a child of the requested folder, `None` if it is hidden
+async fn listing_entry(state: &AppState, parent: &str, entry: &tokio::fs::DirEntry) -> Option<FileEntry> {
+    let name = entry.file_name().to_string_lossy().to_string();
+    // Skip hidden files
+    if state.is_hidden(&name) { return None; }
+    if is_special_file(&entry.path()) { return None; }
+    let is_dir = entry.file_type().await.map(|t| t.is_dir()).unwrap_or(false);
+    if !is_dir && state.hides_blocked(&name) { return None; }
+    let meta = entry.metadata().await.ok();
+    let size = if !is_dir { meta.as_ref().map(|m| m.len()) } else { None };
+
+    Some(FileEntry {
+        kind: file_kind(&name, is_dir),
+        name: name.clone(),
+        path: format!("{}/{}", parent, name),
+        is_dir,
+        size,
+        modified: meta.as_ref().and_then(modified_secs),
+        available: None,
+        file_count: None,
+        totals_truncated: None,
+        protected: None,
+    })
+}
+
+#[axum::debug_handler]
+async fn browse_handler(
+    State(state): State<AppState>,
+    Query(query): Query<BrowseQuery>,
+    access: ShareAccess,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let filter = query.filter();
+    let nocache = query.nocache();
+    let totals = query.totals();
+    let pretty = query.pretty();
+    let req_path = query.path.unwrap_or_else(|| "/".to_string());
+    let req_path_clean = req_path.trim_matches('/');
+
+    let mut entries = if req_path_clean.is_empty() {
+        // Root: list shared folders
+        let mut entries = root_entries(&state);
+        if totals {
+            for entry in entries.iter_mut().filter(|entry| entry.is_dir) {
+                // Protected shares are only counted once unlocked
+                let Ok(dir) = resolve_dir(&state, &access, &entry.path) else {
+                    continue;
+                };
+                let totals = folder_totals(&state, &dir, nocache).await;
+                entry.file_count = Some(totals.file_count);
+                entry.size = Some(totals.size);
+                entry.totals_truncated = totals.truncated.then_some(true);
+            }
+        }
+        entries
+    } else {
+        // Subpath
+        let real_path = resolve_dir(&state, &access, req_path_clean)?;
+        let cached = if nocache { None } else { state.listings.lock().unwrap().get(&real_path) };
+        match cached {
+            // The folder may have been listed under another path, e.g. a
+            // second share or alias holding it, so paths follow this request
+            Some(mut entries) => {
+                for entry in &mut entries {
+                    entry.path = format!("{}/{}", req_path_clean, entry.name);
+                }
+                entries
+            }
+            None => {
+                let mut entries = Vec::new();
+                let mut dir = tokio::fs::read_dir(&real_path).await?;
+                while let Ok(Some(entry)) = dir.next_entry().await {
+                    if let Some(entry) = listing_entry(&state, req_path_clean, &entry).await {
+                        entries.push(entry);
+                    }
+                }
+                state.listings.lock().unwrap().insert(real_path, entries.clone());
+                entries
+            }
+        }
+    };
+
+    entries.retain(|entry| filter.matches(entry));
+    sort_entries(&mut entries, query.sort, query.order);
+
+    let cursor = query.cursor.filter(|cursor| !cursor.is_empty());
+    let paged = query.limit.is_some() || cursor.is_some();
+    let mut next_cursor = None;
+    if paged {
+        if let Some(cursor) = &cursor {
+            let cursor = PageCursor::decode(cursor).ok_or_else(|| ApiError::bad_request("Invalid cursor"))?;
+            if cursor.sort != query.sort || cursor.order != query.order {
+                return Err(ApiError::bad_request("Cursor was issued for a different sort order"));
+            }
+            entries.retain(|entry| cursor.is_before(entry));
+        }
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).max(1);
+        if entries.len() > limit {
+            entries.truncate(limit);
+            next_cursor = entries.last().map(|last| PageCursor::after(last, query.sort, query.order).encode());
+        }
+    }
+
+    let mut response = if prefers_html(&headers) {
+        Html(listing_html(state.options.server_name(), req_path_clean, &entries)).into_response()
+    } else if paged {
+        json_response(&BrowsePage { entries, next_cursor }, pretty)
+    } else {
+        json_response(&entries, pretty)
+    };
+    response.headers_mut().insert(header::VARY, HeaderValue::from_static("accept"));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct DirsQuery {
+    path: Option<String>,
+}
+
+// Subfolders of a folder, or the shared folders at the root, for folder
+// pickers. Only the entry type is checked, so entries carry no size or
+// modification time.
+async fn dirs_handler(
+    State(state): State<AppState>,
+    Query(query): Query<DirsQuery>,
+    access: ShareAccess,
+) -> Result<Json<Vec<FileEntry>>, ApiError> {
+    let req_path = query.path.unwrap_or_default();
+    let req_path = req_path.trim_matches('/');
+
+    let mut entries = if req_path.is_empty() {
+        let mut entries = root_entries(&state);
+        entries.retain(|entry| entry.is_dir);
+        entries
+    } else {
+        let mut entries = Vec::new();
+        let mut dir = open_dir(&state, &access, req_path).await?;
+        while let Ok(Some(entry)) = dir.next_entry().await {
+            if !entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            if state.is_hidden(&name) {
+                continue;
+            }
+            entries.push(FileEntry {
+                kind: file_kind(&name, true),
+                path: format!("{}/{}", req_path, name),
+                name,
+                is_dir: true,
+                size: None,
+                modified: None,
+                available: None,
+                file_count: None,
+                totals_truncated: None,
+                protected: None,
+            });
+        }
+        entries
+    };
+    sort_entries(&mut entries, SortKey::Name, SortOrder::Asc);
+    Ok(Json(entries))
+}
+
+// Counts the visible files under a shared root, see `walk_files` for the
+// limits. Results are reused while the root's mtime is unchanged. That
+// only notices changes to its direct children, so `nocache` forces a walk.
+async fn folder_totals(state: &AppState, dir: &std::path::Path, nocache: bool) -> FolderTotals {
+    let modified = tokio::fs::metadata(dir).await.ok().and_then(|meta| meta.modified().ok());
+    if !nocache {
+        let cache = state.folder_totals.lock().unwrap();
+        if let Some(cached) = cache.get(dir) {
+            if modified.is_some() && cached.modified == modified {
+                return cached.totals;
+            }
+        }
+    }
+
+    let walked = walk_files(state, dir).await;
+    let totals = FolderTotals {
+        file_count: walked.files.len() as u64,
+        size: walked.files.iter().map(|(_, meta)| meta.len()).sum(),
+        truncated: walked.truncated,
+    };
+
+    state.folder_totals.lock().unwrap().insert(dir.to_path_buf(), CachedTotals { modified, totals });
+    totals
+}
+
+fn json_response<T: Serialize>(value: &T, pretty: bool) -> Response {
+    if !pretty {
+        return Json(value).into_response();
+    }
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => ([(header::CONTENT_TYPE, "application/json")], json).into_response(),
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", e.to_string()).into_response(),
+    }
+}
+
+// Whether the Accept header ranks text/html above JSON. JSON wins ties and
+// is the default, so existing API clients are unaffected.
+fn prefers_html(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let mut html = 0.0;
+    let mut json = 0.0;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let media = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match media.as_str() {
+            "text/html" => html = f32::max(html, q),
+            "application/json" => json = f32::max(json, q),
+            _ => {}
+        }
+    }
+    html > json
+}
+
+// Plain directory listing for browsers without JavaScript. Folders link back
+// here, files to the download route.
+fn listing_html(server_name: &str, req_path: &str, entries: &[FileEntry]) -> String {
+    let title = escape_html(&format!("{} - /{}", server_name, req_path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>{0}</title></head><body>\n<h1>{0}</h1>\n<ul>\n",
+        title
+    );
+    if !req_path.is_empty() {
+        let parent = req_path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        html.push_str(&format!("<li><a href=\"/api/browse?path={}\">../</a></li>\n", percent_encode(parent, b"-._~/")));
+    }
+    for entry in entries {
+        let (href, suffix) = if entry.is_dir {
+            (format!("/api/browse?path={}", percent_encode(&entry.path, b"-._~/")), "/".to_string())
+        } else {
+            let size = entry.size.map(|size| format!(" ({} bytes)", size)).unwrap_or_default();
+            (format!("/download/{}", percent_encode(&entry.path, b"-._~/")), size)
+        };
+        html.push_str(&format!(
+            "<li><a href=\"{}\">{}</a>{}</li>\n",
+            href,
+            escape_html(&entry.name),
+            suffix
+        ));
+    }
+    html.push_str("</ul>\n</body></html>\n");
+    html
+}
+
+// Resolve a request path to a shared folder and start reading it
+async fn open_dir(state: &AppState, access: &ShareAccess, req_path: &str) -> Result<tokio::fs::ReadDir, ApiError> {
+    let real_path = resolve_dir(state, access, req_path)?;
+    Ok(tokio::fs::read_dir(real_path).await?)
+}
+
+fn resolve_dir(state: &AppState, access: &ShareAccess, req_path: &str) -> Result<PathBuf, ApiError> {
+    if req_path.contains("..") {
+        return Err(ApiError::forbidden("Invalid path"));
+    }
+    // Resolve path inside lock, then drop lock
+    let real_path = {
+        let folders = state.roots();
+        access.resolve(&folders, req_path)?
+    };
+    let real_path = real_path.ok_or_else(|| ApiError::not_found("Folder not found"))?;
+    if !real_path.is_dir() {
+        return Err(state.missing(req_path, "Folder not found"));
+    }
+    Ok(real_path)
+}
+
+// Text trees are meant for pasting into a chat, keep them short
+const DEFAULT_TREE_DEPTH: usize = 3;
+const TREE_MAX_ENTRIES: usize = 2_000;
+
+#[derive(Deserialize)]
+struct TreeQuery {
+    depth: Option<usize>,
+}
+
+// Plain-text overview of a folder in the style of the `tree` command. Hidden
+// entries are left out; depth is capped by max_walk_depth and the output stops
+// after TREE_MAX_ENTRIES lines.
+async fn tree_handler(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    Query(query): Query<TreeQuery>,
+    access: ShareAccess,
+) -> Result<Response, ApiError> {
+    let root = resolve_dir(&state, &access, &path)?;
+    let max_depth = query.depth.unwrap_or(DEFAULT_TREE_DEPTH).min(state.options.max_walk_depth());
+    let name = zip_folder_name(&path);
+    let tree = tokio::task::spawn_blocking(move || {
+        let mut tree = TextTree { state: &state, out: format!("{}\n", name), entries: 0, max_depth };
+        tree.add_dir(&root, "", 1);
+        tree.out
+    })
+    .await
+    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", e.to_string()))?;
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], tree).into_response())
+}
+
+struct TextTree<'a> {
+    state: &'a AppState,
+    out: String,
+    entries: usize,
+    max_depth: usize,
+}
+
+impl TextTree<'_> {
+    // Folders first, then files, each by name like the browse listing.
+    // Returns false once the entry cap is reached.
+    fn add_dir(&mut self, dir: &std::path::Path, prefix: &str, depth: usize) -> bool {
+        let Ok(read) = std::fs::read_dir(dir) else {
+            return true;
+        };
+        let mut children: Vec<(String, PathBuf, bool)> = read
+            .flatten()
+            .map(|entry| (entry.file_name().to_string_lossy().into_owned(), entry.path()))
+            .filter(|(name, path)| !self.state.is_hidden(name) && !is_special_file(path))
+            .map(|(name, path)| {
+                let is_dir = path.is_dir();
+                (name, path, is_dir)
+            })
+            .collect();
+        children.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+
+        let count = children.len();
+        for (i, (name, path, is_dir)) in children.into_iter().enumerate() {
+            if self.entries >= TREE_MAX_ENTRIES {
+                self.out.push_str(&format!("{}... {} more not shown\n", prefix, count - i));
+                return false;
+            }
+            self.entries += 1;
+            let last = i + 1 == count;
+            let branch = if last { "└── " } else { "├── " };
+            self.out.push_str(&format!("{}{}{}{}\n", prefix, branch, name, if is_dir { "/" } else { "" }));
+            if is_dir && depth < self.max_depth {
+                let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+                if !self.add_dir(&path, &prefix, depth + 1) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+// Same entries as `browse_handler` but written as newline-delimited JSON while
+// the directory is being read, unsorted. Lets the UI render slow folders incrementally.
+async fn browse_stream_handler(
+    State(state): State<AppState>,
+    Query(query): Query<BrowseQuery>,
+    access: ShareAccess,
+) -> Result<Response, ApiError> {
+    let filter = query.filter();
+    let req_path = query.path.unwrap_or_default();
+    let req_path_clean = req_path.trim_matches('/').to_string();
+
+    // Open the folder up front so a bad path is reported before streaming starts
+    let dir = if req_path_clean.is_empty() {
+        None
+    } else {
+        Some(open_dir(&state, &access, &req_path_clean).await?)
+    };
+
+    let (mut w, r) = duplex(16 * 1024);
+
+    tokio::spawn(async move {
+        let Some(mut dir) = dir else {
+            for entry in root_entries(&state).into_iter().filter(|entry| filter.matches(entry)) {
+                if write_ndjson(&mut w, &entry).await.is_err() {
+                    return;
+                }
+            }
+            return;
+        };
+
+        while let Ok(Some(entry)) = dir.next_entry().await {
+            let Some(entry) = listing_entry(&state, &req_path_clean, &entry).await else {
+                continue;
+            };
+            if filter.matches(&entry) {
+                // Writing fails once the client disconnects, which ends the walk
+                if write_ndjson(&mut w, &entry).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from_stream(ReaderStream::new(r)))
+        .unwrap())
+}
+
+async fn write_ndjson<W: AsyncWrite + Unpin, T: Serialize>(w: &mut W, value: &T) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    w.write_all(&line).await
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    /// Throttle this download to the given rate in kilobytes per second.
+    /// Omitting it serves the file at full speed.
+    limit_kbps: Option<u64>,
+    /// Serve with `Content-Disposition: inline` so browsers display the file,
+    /// e.g. `<video src="/download/clip.mp4?inline=true">`
+    #[serde(default)]
+    inline: bool,
+    /// Name the browser saves the file as instead of the name on disk
+    filename: Option<String>,
+    /// Send a `Digest: sha-256=...` header, hashing the file first if its
+    /// checksum isn't cached yet, e.g. `digest=1`
+    digest: Option<String>,
+}
+
+// A file's SHA-256, valid while its size and mtime are unchanged
+struct CachedChecksum {
+    size: u64,
+    modified: Option<SystemTime>,
+    sha256: [u8; 32],
+}
+
+// The cached checksum of `path`, or a freshly computed one when `compute` is
+// set. Hashing reads the whole file, so plain downloads only use the cache.
+async fn file_sha256(
+    state: &AppState,
+    path: &std::path::Path,
+    meta: &std::fs::Metadata,
+    compute: bool,
+) -> std::io::Result<Option<[u8; 32]>> {
+    let modified = meta.modified().ok();
+    if let Some(cached) = state.checksums.lock().unwrap().get(path) {
+        if cached.size == meta.len() && modified.is_some() && cached.modified == modified {
+            return Ok(Some(cached.sha256));
+        }
+    }
+    if !compute {
+        return Ok(None);
+    }
+
+    let file_path = path.to_path_buf();
+    let sha256 = tokio::task::spawn_blocking(move || -> std::io::Result<[u8; 32]> {
+        use sha2::{Digest, Sha256};
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(file_path)?, &mut hasher)?;
+        Ok(hasher.finalize().into())
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+    state.checksums.lock().unwrap().insert(
+        path.to_path_buf(),
+        CachedChecksum { size: meta.len(), modified, sha256 },
+    );
+    Ok(Some(sha256))
+}
+
+// RFC 3230 instance digest
+fn digest_header(sha256: &[u8; 32]) -> String {
+    use base64::Engine;
+    format!("sha-256={}", base64::engine::general_purpose::STANDARD.encode(sha256))
+}
+
+// Rejects download name overrides that could point the browser outside the
+// download folder or don't name anything
+fn download_filename(name: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+    if name.is_empty()
+        || name == "."
+        || name.contains("..")
+        || name.contains(['/', '\\'])
+        || name.chars().any(char::is_control)
+    {
+        return Err(ApiError::bad_request("Invalid filename"));
+    }
+    Ok(name.to_string())
+}
+
+// Players seek with many small range requests, keep each read short
+const RANGE_CHUNK_SIZE: usize = 16 * 1024;
+
+// Parse a single `bytes=` range against a file of `size` bytes into an
+// inclusive (start, end). Ok(None) means serve the whole file: no header, or
+// a form we don't handle such as multiple ranges. Err means unsatisfiable.
+fn parse_range(header: Option<&HeaderValue>, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = header.and_then(|value| value.to_str().ok()).and_then(|value| value.strip_prefix("bytes=")) else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    let range = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        // bytes=500-999 or bytes=500-
+        (Some(start), end) => (start, end.unwrap_or(u64::MAX).min(size.saturating_sub(1))),
+        // bytes=-500, the last 500 bytes
+        (None, Some(suffix)) if suffix > 0 => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        _ => return Ok(None),
+    };
+    if range.0 >= size || range.0 > range.1 {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+// Content-Disposition for a download. Older clients read the ASCII `filename`,
+// others prefer the exact UTF-8 name in `filename*` (RFC 5987).
+fn attachment(filename: &str) -> String {
+    content_disposition("attachment", filename)
+}
+
+fn content_disposition(disposition: &str, filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let encoded = percent_encode(filename, b"!#$&+-.^_`|~");
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, fallback, encoded)
+}
+
+// Percent-encodes every byte except ASCII alphanumerics and those in `keep`
+fn percent_encode(text: &str, keep: &[u8]) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || keep.contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// Head previews are for peeking at logs and CSVs, not a way around downloads
+const DEFAULT_HEAD_BYTES: u64 = 4096;
+const HEAD_MAX_BYTES: u64 = 1024 * 1024;
+
+#[derive(Deserialize)]
+struct HeadQuery {
+    bytes: Option<u64>,
+}
+
+// First bytes of a file with its content type. Files shorter than asked
+// for are returned whole; `X-File-Size` has the full length.
+async fn head_handler(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    Query(query): Query<HeadQuery>,
+    access: ShareAccess,
+) -> Result<Response, ApiError> {
+    let bytes = query.bytes.unwrap_or(DEFAULT_HEAD_BYTES);
+    if bytes == 0 || bytes > HEAD_MAX_BYTES {
+        return Err(ApiError::new(
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "range_not_satisfiable",
+            format!("bytes must be between 1 and {}", HEAD_MAX_BYTES),
+        ));
+    }
+    if path.contains("..") {
+        return Err(ApiError::forbidden("Invalid path"));
+    }
+    let file_path = {
+        let folders = state.roots();
+        access.resolve(&folders, &path)?
+    };
+    let file_path = file_path.ok_or_else(|| ApiError::not_found("File not found"))?;
+    if !file_path.is_file() {
+        return Err(state.missing(&path, "File not found"));
+    }
+    state.check_file_type(&file_path)?;
+    // A preview would be a way around counting the downloads
+    if is_download_limited(&state.download_limits, &file_path) {
+        return Err(ApiError::forbidden("Previews aren't available for files with a download limit"));
+    }
+
+    let file = File::open(&file_path).await?;
+    let size = file.metadata().await?.len();
+    let len = bytes.min(size);
+    let reader = CountedReader::new(file.take(len), &state, false);
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, content_type(&file_path))
+        .header(header::CONTENT_LENGTH, len)
+        .header("x-file-size", size)
+        .body(Body::from_stream(ReaderStream::new(reader)))
+        .unwrap())
+}
+
+// Feeds are polled by readers every few minutes, so a short cache saves
+// walking the folder for each of them
+const FEED_MAX_ITEMS: usize = 50;
+const FEED_CACHE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+struct FeedItem {
+    /// Relative to the folder the feed is for
+    path: String,
+    size: u64,
+    modified: SystemTime,
+}
+
+struct CachedFeed {
+    items: Vec<FeedItem>,
+    fetched_at: Instant,
+}
+
+// RSS 2.0 feed of the most recently modified files under a folder, linking
+// to their downloads
+async fn feed_handler(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    access: ShareAccess,
+    uri: axum::http::Uri,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let req_path = path.trim_matches('/');
+    let dir = resolve_dir(&state, &access, req_path)?;
+    let items = recent_files(&state, &dir).await;
+
+    let base = request_base_url(&uri, &headers);
+    // Feed readers fetch the links on their own, so they carry the password
+    // the feed was opened with
+    let password = access
+        .password
+        .as_ref()
+        .map(|password| format!("?share_password={}", percent_encode(password, b"-._~")))
+        .unwrap_or_default();
+    let title = format!("{} - {}", state.options.server_name(), req_path);
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\"><channel>");
+    xml.push_str(&format!(
+        "<title>{}</title><link>{}/</link><description>Recently changed files in {}</description>",
+        escape_html(&title),
+        escape_html(&base),
+        escape_html(req_path),
+    ));
+    for item in &items {
+        let download_path = percent_encode(&format!("{}/{}", req_path, item.path), b"-._~/");
+        let link = escape_html(&format!("{}/download/{}{}", base, download_path, password));
+        xml.push_str(&format!(
+            "<item><title>{}</title><link>{link}</link><guid isPermaLink=\"false\">{link}#{}</guid><pubDate>{}</pubDate><description>{} bytes</description></item>",
+            escape_html(&item.path),
+            item.modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            httpdate::fmt_http_date(item.modified),
+            item.size,
+        ));
+    }
+    xml.push_str("</channel></rss>\n");
+
+    Ok(([(header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")], xml).into_response())
+}
+
+// Scheme and host the client used to reach us. Behind a TLS proxy the
+// connection itself is plain HTTP, so X-Forwarded-Proto wins.
+fn request_base_url(uri: &axum::http::Uri, headers: &HeaderMap) -> String {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let scheme = header("x-forwarded-proto")
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+        .filter(|scheme| matches!(*scheme, "http" | "https"))
+        .or(uri.scheme_str())
+        .unwrap_or("http");
+    let host = header("host").or(uri.host()).unwrap_or("localhost");
+    format!("{}://{}", scheme, host)
+}
+
+// Newest files under `dir`, among those `walk_files` reaches
+async fn recent_files(state: &AppState, dir: &std::path::Path) -> Vec<FeedItem> {
+    if let Some(cached) = state.feeds.lock().unwrap().get(dir) {
+        if cached.fetched_at.elapsed() < FEED_CACHE_TTL {
+            return cached.items.clone();
+        }
+    }
+
+    let mut items: Vec<FeedItem> = walk_files(state, dir)
+        .await
+        .files
+        .into_iter()
+        .filter_map(|(path, meta)| {
+            Some(FeedItem {
+                path: path.strip_prefix(dir).ok()?.to_string_lossy().replace('\\', "/"),
+                size: meta.len(),
+                modified: meta.modified().unwrap_or(UNIX_EPOCH),
+            })
+        })
+        .collect();
+    items.sort_by_key(|item| std::cmp::Reverse(item.modified));
+    items.truncate(FEED_MAX_ITEMS);
+
+    state.feeds.lock().unwrap().insert(dir.to_path_buf(), CachedFeed { items: items.clone(), fetched_at: Instant::now() });
+    items
+}
+
+// Detailed metadata of a shared file or folder, for admins. Timestamps are
+// seconds since the epoch and missing where the filesystem doesn't keep them.
+#[derive(Serialize)]
+struct FileStat {
+    path: String,
+    is_dir: bool,
+    is_symlink: bool,
+    size: u64,
+    modified: Option<u64>,
+    created: Option<u64>,
+    accessed: Option<u64>,
+    /// Permission bits, e.g. 0o644, on Unix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+    /// FILE_ATTRIBUTE_* flags on Windows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attributes: Option<u32>,
+    readonly: bool,
+}
+
+async fn stat_handler(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    access: ShareAccess,
+) -> Result<Json<FileStat>, ApiError> {
+    if !state.options.file_details {
+        return Err(ApiError::forbidden("File details are disabled on this server"));
+    }
+    if path.contains("..") {
+        return Err(ApiError::forbidden("Invalid path"));
+    }
+    let real_path = {
+        let folders = state.roots();
+        access.resolve(&folders, &path)?
+    };
+    let real_path = real_path.ok_or_else(|| ApiError::not_found("File not found"))?;
+    let meta = tokio::fs::metadata(&real_path)
+        .await
+        .map_err(|_| state.missing(&path, "File not found"))?;
+    let is_symlink = tokio::fs::symlink_metadata(&real_path)
+        .await
+        .is_ok_and(|meta| meta.file_type().is_symlink());
+    let secs = |time: std::io::Result<std::time::SystemTime>| {
+        time.ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+    };
+
+    #[allow(unused_mut)]
+    let mut stat = FileStat {
+        path: path.trim_matches('/').to_string(),
+        is_dir: meta.is_dir(),
+        is_symlink,
+        size: meta.len(),
+        modified: secs(meta.modified()),
+        created: secs(meta.created()),
+        accessed: secs(meta.accessed()),
+        mode: None,
+        uid: None,
+        gid: None,
+        attributes: None,
+        readonly: meta.permissions().readonly(),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        stat.mode = Some(meta.mode() & 0o7777);
+        stat.uid = Some(meta.uid());
+        stat.gid = Some(meta.gid());
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        stat.attributes = Some(meta.file_attributes());
+    }
+    Ok(Json(stat))
+}
+
+async fn file_handler(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path(path): Path<String>,
+    Query(query): Query<DownloadQuery>,
+    access: ShareAccess,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if path.contains("..") {
+        return Err(ApiError::forbidden("Invalid path"));
+    }
+
+    let file_path = {
+        let folders = state.roots();
+        access.resolve(&folders, &path)?
+    };
+    
+    let file_path = file_path.ok_or_else(|| ApiError::not_found("File not found"))?;
+
+    if !file_path.exists() || file_path.is_dir() {
+         return Err(state.missing(&path, "File not found"));
+    }
+    if is_special_file(&file_path) {
+        return Err(ApiError::forbidden("Not a regular file"));
+    }
+    state.check_file_type(&file_path)?;
+
+    let slot = state.reserve_download(&file_path)?;
+    let mut file = File::open(&file_path).await?;
+    let meta = file.metadata().await?;
+    let size = meta.len();
+    let sha256 = file_sha256(&state, &file_path, &meta, is_enabled(query.digest.as_deref())).await?;
+    let filename = match &query.filename {
+        Some(name) => download_filename(name)?,
+        None => file_path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+    };
+    let disposition = content_disposition(if query.inline { "inline" } else { "attachment" }, &filename);
+    let rate = query.limit_kbps.map(|kbps| kbps.saturating_mul(1024));
+
+    // Limited files are always sent whole so ranges can't sidestep the count.
+    // A range whose If-Range no longer matches gets the whole new file.
+    let validators = FileValidators::new(&meta);
+    let range_header = if slot.is_some() || !validators.if_range_matches(&headers) {
+        None
+    } else {
+        headers.get(header::RANGE)
+    };
+    let range = match parse_range(range_header, size) {
+        Ok(range) => range,
+        Err(()) => {
+            return Ok(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                .body(Body::empty())
+                .unwrap());
+        }
+    };
+
+    let mut mime = content_type(&file_path);
+    if mime == "application/octet-stream" && range.is_none() {
+        mime = sniff_content_type(&mut file).await?.unwrap_or(mime);
+    }
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, mime)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_DISPOSITION, disposition);
+    if let Some(last_modified) = &validators.last_modified {
+        response = response.header(header::LAST_MODIFIED, last_modified);
+    }
+    if state.options.precompressed || state.options.gzip_downloads {
+        response = response.header(header::VARY, "accept-encoding");
+    }
+
+    // The variant is sent whole under the original's name and type
+    let variant = match range {
+        None if state.options.precompressed => precompressed_variant(&file_path, &headers).await,
+        _ => None,
+    };
+    if let Some((variant, encoding)) = variant {
+        let len = variant.metadata().await?.len();
+        let stats = state.clone();
+        let file = TrackedReader::new(variant, move |size| {
+            if let Some(slot) = &slot {
+                slot.complete();
+            }
+            state.record_download(path.clone(), path, size, client);
+        });
+        let file = CountedReader::new(ThrottledReader::new(file, rate), &stats, true);
+        return Ok(response
+            .header(header::CONTENT_ENCODING, encoding)
+            .header(header::CONTENT_LENGTH, len)
+            .body(Body::from_stream(ReaderStream::new(file)))
+            .unwrap());
+    }
+
+    if let Some(etag) = &validators.etag {
+        response = response.header(header::ETAG, etag);
+    }
+    // The digest covers the whole file as stored, also for ranges, so
+    // gzipped responses go without
+    let gzip = range.is_none()
+        && state.options.gzip_downloads
+        && size >= GZIP_MIN_SIZE
+        && is_compressible(mime)
+        && accepts_gzip(&headers);
+    if let Some(sha256) = sha256.filter(|_| !gzip) {
+        response = response.header("digest", digest_header(&sha256));
+    }
+
+    // Partial requests only read the bytes asked for and don't count as downloads
+    if let Some((start, end)) = range {
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        let len = end - start + 1;
+        let file = CountedReader::new(ThrottledReader::new(file.take(len), rate), &state, false);
+        return Ok(response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size))
+            .header(header::CONTENT_LENGTH, len)
+            .body(Body::from_stream(ReaderStream::with_capacity(file, RANGE_CHUNK_SIZE)))
+            .unwrap());
+    }
+
+    // Downloads are recorded with the file's size, stats count what is sent
+    let stats = state.clone();
+    let file = TrackedReader::new(file, move |size| {
+        if let Some(slot) = &slot {
+            slot.complete();
+        }
+        state.record_download(path.clone(), path, size, client);
+    });
+    if gzip {
+        let file = GzipEncoder::new(tokio::io::BufReader::new(file));
+        let file = CountedReader::new(ThrottledReader::new(file, rate), &stats, true);
+        return Ok(response
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from_stream(ReaderStream::new(file)))
+            .unwrap());
+    }
+    let file = CountedReader::new(ThrottledReader::new(file, rate), &stats, true);
+    Ok(response
+        .body(Body::from_stream(ReaderStream::new(file)))
+        .unwrap())
+}
+
+// Identify one version of a file for download managers resuming with
+// If-Range. The ETag changes whenever the size or mtime does.
+struct FileValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl FileValidators {
+    fn new(meta: &std::fs::Metadata) -> Self {
+        let modified = meta.modified().ok();
+        let nanos = modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_nanos());
+        Self {
+            etag: nanos.map(|nanos| format!("\"{:x}-{:x}\"", meta.len(), nanos)),
+            last_modified: modified.map(httpdate::fmt_http_date),
+        }
+    }
+
+    // True without If-Range. Otherwise the ETag or the date has to be
+    // exactly the one sent with the file.
+    fn if_range_matches(&self, headers: &HeaderMap) -> bool {
+        let Some(if_range) = headers.get(header::IF_RANGE).and_then(|value| value.to_str().ok()) else {
+            return true;
+        };
+        let if_range = if_range.trim();
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            // Weak tags never match for ranges
+            self.etag.as_deref() == Some(if_range)
+        } else {
+            self.last_modified.as_deref() == Some(if_range)
+        }
+    }
+}
+
+// Text formats worth compressing; media and archives are compressed already
+fn is_compressible(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || ["application/json", "application/xml", "application/wasm", "image/svg+xml"]
+            .iter()
+            .any(|compressible| mime.starts_with(compressible))
+}
+
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    accepts_encoding(headers, "gzip")
+}
+
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT_ENCODING).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    accept.split(',').any(|item| {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        coding.eq_ignore_ascii_case(encoding) && q > 0.0
+    })
+}
+
+// `path.br` or `path.gz` next to the file, preferring brotli, if the client
+// accepts it
+async fn precompressed_variant(path: &std::path::Path, headers: &HeaderMap) -> Option<(File, &'static str)> {
+    for (extension, encoding) in [("br", "br"), ("gz", "gzip")] {
+        if !accepts_encoding(headers, encoding) {
+            continue;
+        }
+        let mut variant = path.as_os_str().to_owned();
+        variant.push(".");
+        variant.push(extension);
+        let variant = PathBuf::from(variant);
+        if !variant.is_file() {
+            continue;
+        }
+        if let Ok(file) = File::open(&variant).await {
+            return Some((file, encoding));
+        }
+    }
+    None
+}
+
+use async_compression::tokio::bufread::GzipEncoder;
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use tokio::io::{duplex, DuplexStream};
+
+type ZipStreamWriter = ZipFileWriter<DuplexStream>;
+type TarStreamWriter = tokio_tar::Builder<DuplexStream>;
+
+// An archive format the walk helpers below can write to. Both formats share
+// the same naming, limits and errors manifest.
+trait ArchiveWriter {
+    async fn add_file(
+        &mut self,
+        source: EntrySource<'_>,
+        entry_name: String,
+        ctx: &mut ZipContext<'_>,
+    ) -> Result<(), ZipAborted>;
+
+    // An entry built in memory, such as the errors manifest
+    async fn add_bytes(&mut self, entry_name: &str, bytes: &[u8]) -> std::io::Result<()>;
+
+    async fn finish(self) -> std::io::Result<()>;
+}
+
+impl ArchiveWriter for ZipStreamWriter {
+    async fn add_file(
+        &mut self,
+        source: EntrySource<'_>,
+        entry_name: String,
+        ctx: &mut ZipContext<'_>,
+    ) -> Result<(), ZipAborted> {
+        zip_file(self, source, entry_name, ctx).await
+    }
+
+    async fn add_bytes(&mut self, entry_name: &str, bytes: &[u8]) -> std::io::Result<()> {
+        let builder = ZipEntryBuilder::new(entry_name.to_string().into(), Compression::Deflate);
+        self.write_entry_whole(builder, bytes).await.map_err(std::io::Error::other)
+    }
+
+    async fn finish(self) -> std::io::Result<()> {
+        self.close().await.map(drop).map_err(std::io::Error::other)
+    }
+}
+
+impl ArchiveWriter for TarStreamWriter {
+    async fn add_file(
+        &mut self,
+        source: EntrySource<'_>,
+        entry_name: String,
+        ctx: &mut ZipContext<'_>,
+    ) -> Result<(), ZipAborted> {
+        let Some((file, meta)) = open_entry(source, &entry_name, ctx).await? else {
+            return Ok(());
+        };
+
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_metadata(&meta);
+        // The header already promised meta.len() bytes, so a file that shrinks
+        // meanwhile is padded with zeros rather than corrupting the stream.
+        // A read error leaves no way to continue and ends the archive.
+        let size = meta.len();
+        let data = file.take(size).chain(tokio::io::repeat(0)).take(size);
+        let data = ProgressReader { inner: data, job: ctx.job };
+        self.append_data(&mut header, &entry_name, data)
+            .await
+            .map_err(|_| ZipAborted::Disconnected)
+    }
+
+    async fn add_bytes(&mut self, entry_name: &str, bytes: &[u8]) -> std::io::Result<()> {
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(unix_timestamp());
+        self.append_data(&mut header, entry_name, bytes).await
+    }
+
+    async fn finish(self) -> std::io::Result<()> {
+        // into_inner writes the end-of-archive marker
+        self.into_inner().await?.shutdown().await
+    }
+}
+
+// Name of the manifest appended to archives when some entries couldn't be added
+const ZIP_ERRORS_ENTRY: &str = "_HFS_ERRORS.txt";
+
+// An archive entry that was skipped, reported in the errors manifest
+struct ZipFailure {
+    path: String,
+    reason: String,
+}
+
+enum ZipAborted {
+    // The client went away, there is no point in writing more entries
+    Disconnected,
+    // One of the ZipLimits was hit, the archive ends here
+    Limit(String),
+}
+
+// Guards against runaway archives, e.g. a symlink pointing back up the tree
+#[derive(Clone, Copy)]
+struct ZipLimits {
+    max_entries: usize,
+    max_bytes: u64,
+    max_depth: usize,
+}
+
+// Settings and bookkeeping for one archive, shared by the zip helpers
+struct ZipContext<'a> {
+    compression: Compression,
+    failures: Vec<ZipFailure>,
+    job: Option<&'a ZipJob>,
+    limits: ZipLimits,
+    exclude: GlobSet,
+    file_types: Arc<FileTypes>,
+    download_limits: Arc<Mutex<HashMap<PathBuf, DownloadLimit>>>,
+    read_ahead: usize,
+    entries: usize,
+    bytes: u64,
+}
+
+impl<'a> ZipContext<'a> {
+    fn new(compression: Compression, limits: ZipLimits, job: Option<&'a ZipJob>) -> Self {
+        Self {
+            compression,
+            failures: Vec::new(),
+            job,
+            limits,
+            exclude: GlobSet::empty(),
+            file_types: Arc::default(),
+            download_limits: Arc::default(),
+            read_ahead: 0,
+            entries: 0,
+            bytes: 0,
+        }
+    }
+
+    fn reading_ahead(mut self, files: usize) -> Self {
+        self.read_ahead = files;
+        self
+    }
+
+    fn excluding(mut self, exclude: GlobSet) -> Self {
+        self.exclude = exclude;
+        self
+    }
+
+    // Leaves out files the server refuses to send on their own
+    fn permitting(mut self, file_types: Arc<FileTypes>) -> Self {
+        self.file_types = file_types;
+        self
+    }
+
+    // Leaves out files with a download limit. Their downloads are only
+    // counted when they're fetched on their own.
+    fn withholding(mut self, download_limits: Arc<Mutex<HashMap<PathBuf, DownloadLimit>>>) -> Self {
+        self.download_limits = download_limits;
+        self
+    }
+
+    fn withholds(&self, path: &std::path::Path) -> bool {
+        is_download_limited(&self.download_limits, path)
+    }
+
+    fn permits(&self, path: &std::path::Path) -> bool {
+        self.file_types.permits(&path.file_name().unwrap_or_default().to_string_lossy())
+    }
+
+    fn fail(&mut self, path: String, reason: impl Into<String>) {
+        self.failures.push(ZipFailure { path, reason: reason.into() });
+    }
+
+    // Count a file of `size` bytes against the limits before it is added
+    fn reserve(&mut self, size: u64) -> Result<(), ZipAborted> {
+        if self.entries >= self.limits.max_entries {
+            return Err(ZipAborted::Limit(format!("more than {} files", self.limits.max_entries)));
+        }
+        if self.bytes.saturating_add(size) > self.limits.max_bytes {
+            return Err(ZipAborted::Limit(format!("more than {} bytes", self.limits.max_bytes)));
+        }
+        self.entries += 1;
+        self.bytes += size;
+        Ok(())
+    }
+}
+
+// Add a single file to the archive. Problems with the source file are recorded
+// and skipped; failing to write to the archive itself aborts.
+async fn zip_file(
+    writer: &mut ZipStreamWriter,
+    source: EntrySource<'_>,
+    entry_name: String,
+    ctx: &mut ZipContext<'_>,
+) -> Result<(), ZipAborted> {
+    let Some((file, _)) = open_entry(source, &entry_name, ctx).await? else {
+        return Ok(());
+    };
+
+    // Read-ahead data goes through the same streamed entry as a file on disk,
+    // so the archive is identical either way
+    let builder = ZipEntryBuilder::new(entry_name.clone().into(), ctx.compression);
+    let entry_writer = writer.write_entry_stream(builder).await.map_err(|_| ZipAborted::Disconnected)?;
+    let mut compat_writer = entry_writer.compat_write();
+    let mut file = ProgressReader { inner: file, job: ctx.job };
+    let copied = tokio::io::copy(&mut file, &mut compat_writer).await;
+    compat_writer.into_inner().close().await.map_err(|_| ZipAborted::Disconnected)?;
+
+    if let Err(e) = copied {
+        ctx.fail(entry_name, format!("Incomplete, read failed: {}", e));
+    }
+    Ok(())
+}
+
+// Where an entry's data comes from: the file itself, or its contents read
+// ahead into memory by `archive_dir`
+enum EntrySource<'p> {
+    Disk(&'p std::path::Path),
+    Memory(std::fs::Metadata, Vec<u8>),
+}
+
+type EntryReader = Box<dyn AsyncRead + Unpin + Send>;
+
+// Open an entry's data and count it against the limits. A file that can't be
+// opened is recorded as a failure and skipped with `None`.
+async fn open_entry(
+    source: EntrySource<'_>,
+    entry_name: &str,
+    ctx: &mut ZipContext<'_>,
+) -> Result<Option<(EntryReader, std::fs::Metadata)>, ZipAborted> {
+    let opened: std::io::Result<(EntryReader, std::fs::Metadata)> = match source {
+        EntrySource::Disk(path) => match File::open(path).await {
+            Ok(file) => file.metadata().await.map(|meta| (Box::new(file) as EntryReader, meta)),
+            Err(e) => Err(e),
+        },
+        EntrySource::Memory(meta, data) => Ok((Box::new(std::io::Cursor::new(data)), meta)),
+    };
+    let (data, meta) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            ctx.fail(entry_name.to_string(), e.to_string());
+            return Ok(None);
+        }
+    };
+    ctx.reserve(meta.len())?;
+
+    if let Some(job) = ctx.job {
+        *job.current_file.lock().unwrap() = entry_name.to_string();
+    }
+    Ok(Some((data, meta)))
+}
+
+// Read a small file into memory on another task. `None` leaves the file to
+// be streamed from disk: it is too big, unreadable or changed while read.
+fn read_ahead(path: PathBuf) -> tokio::task::JoinHandle<Option<(std::fs::Metadata, Vec<u8>)>> {
+    tokio::spawn(async move {
+        let meta = tokio::fs::metadata(&path).await.ok()?;
+        if !meta.is_file() || meta.len() > ZIP_READ_AHEAD_MAX_FILE_SIZE {
+            return None;
+        }
+        let data = tokio::fs::read(&path).await.ok()?;
+        (data.len() as u64 == meta.len()).then_some((meta, data))
+    })
+}
+
+// Compile the exclude patterns of one archive request. Blank patterns are
+// ignored, an invalid one fails the request.
+fn exclude_globs<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Result<GlobSet, ApiError> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns.into_iter().map(str::trim).filter(|pattern| !pattern.is_empty()) {
+        let glob = Glob::new(pattern)
+            .map_err(|e| ApiError::bad_request(format!("Invalid exclude pattern {}: {}", pattern, e)))?;
+        builder.add(glob);
+    }
+    builder.build().map_err(|e| ApiError::bad_request(e.to_string()))
+}
+
+// Excludes match either an entry's name, so `node_modules` is skipped at any
+// depth, or its path below the folder being archived
+fn is_excluded(exclude: &GlobSet, dir: &std::path::Path, path: &std::path::Path) -> bool {
+    let relative = path.strip_prefix(dir).unwrap_or(path);
+    exclude.is_match(relative) || path.file_name().is_some_and(|name| exclude.is_match(name))
+}
+
+// Archive entry name for `path` found while walking `dir`. Entries are named
+// `prefix/<path inside dir>`, so a folder zip keeps the folder as a wrapper.
+// An empty prefix flattens the archive: extracting it dumps the folder's
+// contents directly into the destination.
+fn zip_entry_name(prefix: &str, dir: &std::path::Path, path: &std::path::Path) -> String {
+    let sub_rel = path.strip_prefix(dir).unwrap_or(path).to_string_lossy();
+    if prefix.is_empty() {
+        sub_rel.into_owned()
+    } else {
+        format!("{}/{}", prefix, sub_rel)
+    }
+}
+
+// Files and subfolders of `dir`, each sorted by name so the same folder
+// always produces the same archive layout
+async fn read_dir_sorted(dir: &std::path::Path) -> std::io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.is_dir() {
+            dirs.push(path);
+        } else if !is_special_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    dirs.sort();
+    Ok((files, dirs))
+}
+
+struct WalkedFiles {
+    files: Vec<(PathBuf, std::fs::Metadata)>,
+    /// Stopped at the zip entry limit or skipped folders deeper than
+    /// `max_walk_depth`
+    truncated: bool,
+}
+
+// Visible files under `dir` with their metadata, for walks that only need a
+// list of files. Archives walk on their own since they report each problem.
+// Like archives, it leaves out files that can't be downloaded by link.
+async fn walk_files(state: &AppState, dir: &std::path::Path) -> WalkedFiles {
+    let max_entries = state.options.zip_limits().max_entries;
+    let max_depth = state.options.max_walk_depth();
+    let visible = |path: &PathBuf| {
+        !path.file_name().is_some_and(|name| state.is_hidden(&name.to_string_lossy()))
+    };
+    let downloadable = |path: &PathBuf| {
+        state.file_types.permits(&path.file_name().unwrap_or_default().to_string_lossy())
+            && !is_download_limited(&state.download_limits, path)
+    };
+    let mut walked = WalkedFiles { files: Vec::new(), truncated: false };
+    let mut stack = vec![(dir.to_path_buf(), 0)];
+    while let Some((current_dir, depth)) = stack.pop() {
+        if depth > max_depth {
+            walked.truncated = true;
+            continue;
+        }
+        let Ok((files, dirs)) = read_dir_sorted(&current_dir).await else {
+            continue;
+        };
+        for path in files.into_iter().filter(|path| visible(path) && downloadable(path)) {
+            if walked.files.len() >= max_entries {
+                walked.truncated = true;
+                return walked;
+            }
+            if let Ok(meta) = tokio::fs::metadata(&path).await {
+                walked.files.push((path, meta));
+            }
+        }
+        stack.extend(dirs.into_iter().filter(|path| visible(path)).map(|path| (path, depth + 1)));
+    }
+    walked
+}
+
+// Recursively add the files under `dir`, see `zip_entry_name` for naming.
+// A folder's files come before its subfolders, both in name order. Going
+// deeper than the depth limit stops the archive, as symlink loops would.
+async fn archive_dir(
+    writer: &mut impl ArchiveWriter,
+    dir: &std::path::Path,
+    prefix: &str,
+    ctx: &mut ZipContext<'_>,
+) -> Result<(), ZipAborted> {
+    let entry_name = |path: &std::path::Path| zip_entry_name(prefix, dir, path);
+
+    let mut walk = ArchiveWalk::new(dir, ctx);
+    while let Some(step) = walk.next().await {
+        let files = match step {
+            WalkStep::Files(files) => files,
+            WalkStep::Unreadable(folder, e) => {
+                ctx.fail(entry_name(&folder), e.to_string());
+                continue;
+            }
+            WalkStep::TooDeep(folder) => {
+                return Err(ZipAborted::Limit(format!(
+                    "{} is nested more than {} folders deep",
+                    entry_name(&folder),
+                    ctx.limits.max_depth
+                )));
+            }
+        };
+        // Files are added in order while up to `read_ahead` of the next
+        // ones are being read in the background
+        let mut pending = VecDeque::new();
+        let mut files = files.into_iter();
+        loop {
+            while pending.len() < ctx.read_ahead {
+                let Some(path) = files.next() else { break };
+                let read = read_ahead(path.clone());
+                pending.push_back((path, Some(read)));
+            }
+            let Some((path, read)) = pending.pop_front().or_else(|| files.next().map(|path| (path, None))) else {
+                break;
+            };
+            let source = match read {
+                Some(read) => match read.await {
+                    Ok(Some((meta, data))) => EntrySource::Memory(meta, data),
+                    _ => EntrySource::Disk(&path),
+                },
+                None => EntrySource::Disk(&path),
+            };
+            writer.add_file(source, entry_name(&path), ctx).await?;
+        }
+    }
+    Ok(())
+}
+
+// The walk a folder archive follows: a folder's files in name order, then
+// each of its subfolders the same way. The manifest and file list take the
+// same walk, so they describe exactly what the archive holds.
+struct ArchiveWalk {
+    root: PathBuf,
+    exclude: GlobSet,
+    file_types: Arc<FileTypes>,
+    download_limits: Arc<Mutex<HashMap<PathBuf, DownloadLimit>>>,
+    max_depth: usize,
+    stack: Vec<(PathBuf, usize)>,
+}
+
+enum WalkStep {
+    // Files of the next folder that belong in the archive
+    Files(Vec<PathBuf>),
+    Unreadable(PathBuf, std::io::Error),
+    // A folder nested deeper than the limit, which ends the walk
+    TooDeep(PathBuf),
+}
+
+impl ArchiveWalk {
+    fn new(root: &std::path::Path, ctx: &ZipContext<'_>) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            exclude: ctx.exclude.clone(),
+            file_types: ctx.file_types.clone(),
+            download_limits: ctx.download_limits.clone(),
+            max_depth: ctx.limits.max_depth,
+            stack: vec![(root.to_path_buf(), 0)],
+        }
+    }
+
+    async fn next(&mut self) -> Option<WalkStep> {
+        let (dir, depth) = self.stack.pop()?;
+        if depth > self.max_depth {
+            self.stack.clear();
+            return Some(WalkStep::TooDeep(dir));
+        }
+        let (mut files, mut dirs) = match read_dir_sorted(&dir).await {
+            Ok(entries) => entries,
+            Err(e) => return Some(WalkStep::Unreadable(dir, e)),
+        };
+        files.retain(|path| {
+            !is_excluded(&self.exclude, &self.root, path)
+                && self.file_types.permits(&path.file_name().unwrap_or_default().to_string_lossy())
+                && !is_download_limited(&self.download_limits, path)
+        });
+        dirs.retain(|path| !is_excluded(&self.exclude, &self.root, path));
+        // Pushed in reverse so they are popped in name order
+        self.stack.extend(dirs.into_iter().rev().map(|dir| (dir, depth + 1)));
+        Some(WalkStep::Files(files))
+    }
+}
+
+// Append the errors manifest if anything was skipped, then finish the archive.
+// An archive cut short by a limit is still finished so the reason can be read.
+async fn finish_archive(mut writer: impl ArchiveWriter, ctx: ZipContext<'_>, result: Result<(), ZipAborted>) {
+    let mut failures = ctx.failures;
+    match result {
+        Ok(()) => {}
+        Err(ZipAborted::Disconnected) => return,
+        Err(ZipAborted::Limit(reason)) => {
+            log::error!("Zip stopped early: {}", reason);
+            failures.push(ZipFailure {
+                path: "(archive)".to_string(),
+                reason: format!("Stopped early, {}", reason),
+            });
+        }
+    }
+    if !failures.is_empty() {
+        let mut manifest = String::from("The following entries could not be added to this archive:\n\n");
+        for failure in &failures {
+            log::warn!("Skipped {} while zipping: {}", failure.path, failure.reason);
+            manifest.push_str(&format!("{}: {}\n", failure.path, failure.reason));
+        }
+        if let Err(e) = writer.add_bytes(ZIP_ERRORS_ENTRY, manifest.as_bytes()).await {
+            log::warn!("Failed to write archive errors manifest: {}", e);
+        }
+    }
+    let _ = writer.finish().await;
+}
+
+// Jobs are created by /zip/folder/start and watched over /zip/progress/:id
+// while the archive itself streams from /zip/folder/*path?job=<id>
+const ZIP_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+const ZIP_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+struct ZipJob {
+    path: String,
+    total: u64,
+    processed: AtomicU64,
+    current_file: Mutex<String>,
+    finished: AtomicBool,
+    created: Instant,
+}
+
+impl ZipJob {
+    fn new(path: String, total: u64) -> Self {
+        Self {
+            path,
+            total,
+            processed: AtomicU64::new(0),
+            current_file: Mutex::new(String::new()),
+            finished: AtomicBool::new(false),
+            created: Instant::now(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ZipProgressEvent {
+    processed: u64,
+    total: u64,
+    current_file: String,
+    done: bool,
+}
+
+#[derive(Deserialize)]
+struct ZipStartRequest {
+    path: String,
+    /// The `exclude` globs the archive will be requested with, so the
+    /// estimate leaves out the same files
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ZipStartResponse {
+    job: String,
+    total: u64,
+    /// The archive will stop early or skip something, at a limit such as
+    /// `max_walk_depth` or at files it can't read
+    truncated: bool,
+}
+
+async fn zip_start_handler(
+    State(state): State<AppState>,
+    access: ShareAccess,
+    Json(payload): Json<ZipStartRequest>,
+) -> Result<Json<ZipStartResponse>, ApiError> {
+    let path = payload.path.trim_matches('/').to_string();
+    let target_path = resolve_dir(&state, &access, &path)?;
+    let exclude = exclude_globs(payload.exclude.iter().map(String::as_str))?;
+    // The progress estimate is the size of what the archive will hold
+    let ctx = state.zip_context(Compression::Deflate, None, exclude);
+    let archived = archived_files(&target_path, &ctx).await;
+    let total = archived.files.iter().map(|file| file.size).sum();
+    let job = state.start_zip_job(path, total)?;
+    Ok(Json(ZipStartResponse { job, total, truncated: !archived.complete }))
+}
+
+// Server-sent events with the job's progress until the archive is finished.
+// The file names it reports need the password of the job's share.
+async fn zip_progress_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    access: ShareAccess,
+) -> Result<Response, ApiError> {
+    let job = state.zip_job(&id).ok_or_else(|| ApiError::not_found("Zip job not found"))?;
+    access.resolve(&state.roots(), &job.path)?;
+
+    let (mut w, r) = duplex(4 * 1024);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ZIP_PROGRESS_INTERVAL);
+        loop {
+            interval.tick().await;
+            let done = job.finished.load(Ordering::Relaxed);
+            let event = ZipProgressEvent {
+                processed: job.processed.load(Ordering::Relaxed),
+                total: job.total,
+                current_file: job.current_file.lock().unwrap().clone(),
+                done,
+            };
+            let data = serde_json::to_string(&event).unwrap_or_default();
+            if w.write_all(format!("data: {}\n\n", data).as_bytes()).await.is_err() {
+                // The client went away, nobody is left to watch this job
+                state.end_zip_job(&id);
+                return;
+            }
+            if done {
+                return;
+            }
+        }
+    });
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from_stream(ReaderStream::new(r)))
+        .unwrap())
+}
+
+#[derive(Deserialize)]
+struct ZipQuery {
+    /// Omit the enclosing folder from entry names
+    #[serde(default)]
+    flatten: bool,
+    /// Store files uncompressed, the layout /zip/manifest describes
+    #[serde(default)]
+    stored: bool,
+    /// Stored zip with a known length, an ETag and range support, so a
+    /// dropped download can be resumed, e.g. `deterministic=1`
+    deterministic: Option<String>,
+    /// Progress job from /zip/folder/start
+    job: Option<String>,
+    /// Comma-separated globs of files and folders to leave out
+    exclude: Option<String>,
+}
+
+impl ZipQuery {
+    fn exclude(&self) -> Result<GlobSet, ApiError> {
+        exclude_globs(self.exclude.as_deref().unwrap_or_default().split(','))
+    }
+}
+
+async fn zip_folder_handler(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path(path): Path<String>,
+    Query(query): Query<ZipQuery>,
+    access: ShareAccess,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let target_path = resolve_dir(&state, &access, &path)?;
+    let exclude = query.exclude()?;
+    let wants_totals = headers
+        .get("x-hfs-manifest")
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"true"));
+
+    // A job only reports on the folder it was started for
+    let job = query
+        .job
+        .and_then(|id| Some((state.zip_job(&id)?, id)))
+        .filter(|(job, _)| job.path == path.trim_matches('/'));
+
+    let zip_name = zip_folder_name(&path);
+    let prefix = if query.flatten { String::new() } else { zip_name.clone() };
+
+    let layout = if is_enabled(query.deterministic.as_deref()) {
+        let ctx = state.zip_context(Compression::Stored, None, exclude.clone());
+        let archived = archived_files(&target_path, &ctx).await;
+        Some(StoredZipLayout::new(&prefix, &target_path, &archived)?)
+    } else {
+        None
+    };
+    let range = match &layout {
+        Some(layout) if layout.validators.if_range_matches(&headers) => {
+            match parse_range(headers.get(header::RANGE), layout.len) {
+                Ok(range) => range,
+                Err(()) => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(header::CONTENT_RANGE, format!("bytes */{}", layout.len))
+                        .body(Body::empty())
+                        .unwrap());
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let (w, r) = duplex(state.options.zip_buffer_size());
+    let (totals_tx, totals_rx) = tokio::sync::oneshot::channel();
+    // A range is cut from a fresh copy of the archive, which only works
+    // because it comes out the same every time. Like file ranges it isn't
+    // recorded as a download.
+    let body = if let Some((start, end)) = range {
+        let r = SkipReader { inner: r, skip: start }.take(end - start + 1);
+        Body::from_stream(ReaderStream::new(CountedReader::new(r, &state, false)))
+    } else {
+        let share_path = path.trim_matches('/').to_string();
+        let zip_path = format!("{}.zip", share_path);
+        let recorder = state.clone();
+        let r = CountedReader::new(r, &state, true);
+        let r = TrackedReader::new(r, move |size| recorder.record_download(zip_path, share_path, size, client));
+        let stream = ReaderStream::new(r);
+        if wants_totals {
+            Body::new(TotalsTrailers { inner: Body::from_stream(stream), totals: Some(totals_rx) })
+        } else {
+            Body::from_stream(stream)
+        }
+    };
+
+    let compression = if query.stored || layout.is_some() { Compression::Stored } else { Compression::Deflate };
+
+    tokio::spawn(async move {
+        let mut writer = ZipFileWriter::with_tokio(w);
+        let mut ctx = state.zip_context(compression, job.as_ref().map(|(job, _)| job.as_ref()), exclude);
+        let result = archive_dir(&mut writer, &target_path, &prefix, &mut ctx).await;
+        let totals = ArchiveTotals { file_count: ctx.entries, total_bytes: ctx.bytes };
+        finish_archive(writer, ctx, result).await;
+        let _ = totals_tx.send(totals);
+        // Watchers hold their own reference and still see the final state
+        if let Some((job, id)) = job {
+            job.finished.store(true, Ordering::Relaxed);
+            state.end_zip_job(&id);
+        }
+    });
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/zip")
+        .header(header::CONTENT_DISPOSITION, attachment(&format!("{}.zip", zip_name)));
+    if let Some(layout) = &layout {
+        response = response
+            .header(header::ACCEPT_RANGES, "bytes")
+            .header(header::ETAG, layout.validators.etag.as_deref().unwrap_or_default());
+        response = match range {
+            Some((start, end)) => response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, layout.len))
+                .header(header::CONTENT_LENGTH, end - start + 1),
+            None => response.header(header::CONTENT_LENGTH, layout.len),
+        };
+    }
+    if wants_totals && range.is_none() {
+        response = response.header(header::TRAILER, "x-hfs-file-count, x-hfs-total-bytes");
+    }
+    Ok(response.body(body).unwrap())
 }
 
-pub async fn start_server(
-    port: u16,
-    shared_folders: Vec<String>,
-    mut shutdown_rx: broadcast::Receiver<()>,
-) -> Result<(), String> {
-    let state = AppState {
-        shared_folders: Arc::new(Mutex::new(shared_folders)),
-    };
-
-    let app = Router::new()
-        .route("/", get(root_handler))
-        .route("/api/browse", get(browse_handler))
-        .route("/download/*path", get(file_handler))
-        .route("/zip/folder/*path", get(zip_folder_handler))
-        .route("/zip/selection", axum::routing::post(zip_selection_handler))
-        .with_state(state);
+// Size and validator of a deterministic zip, worked out from the listing
+// before any of it is written. The bytes only come out the same while the
+// folder doesn't change: files are stored, never deflated, in the sorted
+// order archive_dir walks them, with fixed timestamps. A file that can't be
+// read halfway through still breaks the promise, and the response is cut off.
+struct StoredZipLayout {
+    len: u64,
+    validators: FileValidators,
+}
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(addr).await.map_err(|e| e.to_string())?;
+impl StoredZipLayout {
+    fn new(prefix: &str, dir: &std::path::Path, archived: &ArchivedFiles) -> Result<Self, ApiError> {
+        use sha2::{Digest, Sha256};
 
-    println!("Server listening on {}", addr);
+        // An archive cut short by a limit or a folder it can't read ends in
+        // an errors manifest whose size isn't known up front
+        if !archived.complete {
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "not_deterministic",
+                "Folder can't be zipped deterministically, it is over the zip limits or can't be read in full",
+            ));
+        }
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async move {
-            shutdown_rx.recv().await.ok();
-            println!("Server shutting down");
+        let files = &archived.files;
+        let mut hasher = Sha256::new();
+        // async_zip only adds the zip64 end records once it wrote an entry
+        let mut len = ZIP_END_RECORD_LEN;
+        if !files.is_empty() {
+            len += ZIP64_END_RECORDS_LEN;
+        }
+        for file in files {
+            let name = zip_entry_name(prefix, dir, &file.path);
+            len += ZIP_LOCAL_HEADER_LEN + ZIP_STREAM_EXTRA_LEN + file.size + ZIP_DATA_DESCRIPTOR_LEN;
+            len += ZIP_CENTRAL_HEADER_LEN + ZIP_CENTRAL_EXTRA_LEN + 2 * name.len() as u64;
+            let modified = file
+                .modified
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_nanos());
+            hasher.update(format!("{}\0{}\0{}\0", name, file.size, modified));
+        }
+        let digest = hasher.finalize();
+        let etag = digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        Ok(Self {
+            len,
+            validators: FileValidators { etag: Some(format!("\"zip-{}\"", etag)), last_modified: None },
         })
-        .await
-        .map_err(|e| e.to_string())?;
-
-    Ok(())
+    }
 }
 
-// Helper to resolve a relative URL path to a real file path based on shared items
-fn resolve_path(shared_items: &[String], relative_path: &str) -> Option<PathBuf> {
-    let relative_path = relative_path.trim_matches('/');
-    if relative_path.is_empty() { return None; }
+// Reads past the first `skip` bytes of `inner` without handing them out
+struct SkipReader<R> {
+    inner: R,
+    skip: u64,
+}
 
-    // Iterate over shared items
-    for item in shared_items {
-        let item_path = PathBuf::from(item);
-        let item_name = item_path.file_name()?.to_string_lossy();
-        
-        if relative_path == item_name {
-            return Some(item_path);
-        } else if relative_path.starts_with(&format!("{}/", item_name)) {
-            let rest = &relative_path[item_name.len() + 1..];
-            return Some(item_path.join(rest));
+impl<R: AsyncRead + Unpin> AsyncRead for SkipReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let mut scratch = [0u8; 8192];
+        while self.skip > 0 {
+            let want = scratch.len().min(self.skip.try_into().unwrap_or(usize::MAX));
+            let mut skipped = ReadBuf::new(&mut scratch[..want]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut skipped))?;
+            if skipped.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            self.skip -= skipped.filled().len() as u64;
         }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
     }
-    None
 }
 
-#[derive(Serialize)]
-struct FileEntry {
-    name: String,
-    path: String,
-    is_dir: bool,
-    size: Option<u64>,
+struct ArchiveTotals {
+    file_count: usize,
+    total_bytes: u64,
 }
 
-#[derive(Deserialize)]
-struct BrowseQuery {
-    path: Option<String>,
+// Zip body followed by `X-HFS-File-Count` and `X-HFS-Total-Bytes` trailers,
+// sent when a client asks with `X-HFS-Manifest: true`. The totals are counted
+// while the archive is written, so nothing is delayed. HTTP/1.1 clients also
+// need to send `TE: trailers` or the server leaves them out.
+struct TotalsTrailers {
+    inner: Body,
+    totals: Option<tokio::sync::oneshot::Receiver<ArchiveTotals>>,
 }
 
-#[axum::debug_handler]
-async fn browse_handler(
-    State(state): State<AppState>,
-    Query(query): Query<BrowseQuery>,
-) ->  impl IntoResponse {
-    let req_path = query.path.unwrap_or_else(|| "/".to_string());
-    let req_path_clean = req_path.trim_matches('/');
-
-    let mut entries = Vec::new();
+impl http_body::Body for TotalsTrailers {
+    type Data = axum::body::Bytes;
+    type Error = axum::Error;
 
-    if req_path_clean.is_empty() {
-        // Root: list shared folders
-        // We hold lock only here, no awaits
-        let folders = state.shared_folders.lock().unwrap();
-        for folder in folders.iter() {
-            let path = PathBuf::from(folder);
-            if let Some(name) = path.file_name() {
-                let name_str = name.to_string_lossy().to_string();
-                entries.push(FileEntry {
-                    name: name_str.clone(),
-                    path: name_str,
-                    is_dir: path.is_dir(),
-                    size: if path.is_file() { path.metadata().ok().map(|m| m.len()) } else { None },
-                });
-            }
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        if let Some(frame) = ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+            return Poll::Ready(Some(frame));
         }
-    } else {
-        // Subpath
-        // Resolve path inside lock, then drop lock
-        let real_path = {
-            let folders = state.shared_folders.lock().unwrap();
-            resolve_path(&folders, req_path_clean)
+        let Some(totals) = self.totals.as_mut() else {
+            return Poll::Ready(None);
         };
-
-        if let Some(real_path) = real_path {
-            if let Ok(mut dir) = tokio::fs::read_dir(real_path).await {
-                while let Ok(Some(entry)) = dir.next_entry().await {
-                   let name = entry.file_name().to_string_lossy().to_string();
-                   // Skip hidden files
-                   if name.starts_with('.') { continue; }
-                   let is_dir = entry.file_type().await.map(|t| t.is_dir()).unwrap_or(false);
-                   let size = if !is_dir { entry.metadata().await.ok().map(|m| m.len()) } else { None };
-                   
-                   entries.push(FileEntry {
-                       name: name.clone(),
-                       path: format!("{}/{}", req_path_clean, name),
-                       is_dir,
-                       size,
-                   });
-                }
-            }
-        }
+        let totals = ready!(Pin::new(totals).poll(cx));
+        self.totals = None;
+        // The archive task went away without finishing, there is nothing to report
+        let Ok(totals) = totals else {
+            return Poll::Ready(None);
+        };
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-hfs-file-count", HeaderValue::from(totals.file_count));
+        trailers.insert("x-hfs-total-bytes", HeaderValue::from(totals.total_bytes));
+        Poll::Ready(Some(Ok(http_body::Frame::trailers(trailers))))
     }
-    
-    // Sort directories first, then files
-    entries.sort_by(|a, b| {
-        if a.is_dir == b.is_dir {
-            a.name.cmp(&b.name)
-        } else if a.is_dir {
-            std::cmp::Ordering::Less
-        } else {
-            std::cmp::Ordering::Greater
-        }
-    });
+}
 
-    Json(entries)
+// Folder zips are named after the last segment of the request path, so a
+// share's alias is used rather than its name on disk
+fn zip_folder_name(path: &str) -> String {
+    path.trim_matches('/').rsplit('/').next().unwrap_or_default().to_string()
 }
 
-async fn file_handler(
+// Byte layout of each entry async_zip streams: the local file header, the
+// name, the zip64 extra field it always adds, the data and a data descriptor
+const ZIP_LOCAL_HEADER_LEN: u64 = 30;
+const ZIP_STREAM_EXTRA_LEN: u64 = 20;
+const ZIP_DATA_DESCRIPTOR_LEN: u64 = 16;
+// What follows the entries: a central directory record per entry, repeating
+// the name with a zip64 field of its own, then the zip64 end record and its
+// locator, and the classic end record
+const ZIP_CENTRAL_HEADER_LEN: u64 = 46;
+const ZIP_CENTRAL_EXTRA_LEN: u64 = 28;
+const ZIP64_END_RECORDS_LEN: u64 = 56 + 20;
+const ZIP_END_RECORD_LEN: u64 = 22;
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    name: String,
+    /// Where the entry's data starts in the archive
+    offset: u64,
+    size: u64,
+}
+
+// Where each file's data lands in /zip/folder/*path?stored=true, so a client
+// can pick members out of the archive without unpacking it. Only the stored
+// layout is predictable, and only while the folder doesn't change between the
+// two requests.
+async fn zip_manifest_handler(
     State(state): State<AppState>,
     Path(path): Path<String>,
-) -> Result<Response, (StatusCode, String)> {
-    if path.contains("..") {
-        return Err((StatusCode::FORBIDDEN, "Invalid path".to_string()));
-    }
-
-    let file_path = {
-        let folders = state.shared_folders.lock().unwrap();
-        resolve_path(&folders, &path)
-    };
-    
-    let file_path = file_path.ok_or((StatusCode::NOT_FOUND, "File not found".to_string()))?;
+    Query(query): Query<ZipQuery>,
+    access: ShareAccess,
+) -> Result<Json<Vec<ManifestEntry>>, ApiError> {
+    let target_path = resolve_dir(&state, &access, &path)?;
+    let exclude = query.exclude()?;
+    let zip_name = zip_folder_name(&path);
+    let prefix = if query.flatten { String::new() } else { zip_name };
 
-    if !file_path.exists() || file_path.is_dir() {
-         return Err((StatusCode::NOT_FOUND, "File not found".to_string()));
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    let ctx = state.zip_context(Compression::Stored, None, exclude);
+    for file in archived_files(&target_path, &ctx).await.files {
+        let name = zip_entry_name(&prefix, &target_path, &file.path);
+        let data_offset = offset + ZIP_LOCAL_HEADER_LEN + name.len() as u64 + ZIP_STREAM_EXTRA_LEN;
+        offset = data_offset + file.size + ZIP_DATA_DESCRIPTOR_LEN;
+        entries.push(ManifestEntry { name, offset: data_offset, size: file.size });
     }
-
-    let file = File::open(&file_path).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
-
-    let filename = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
-    
-    Ok(Response::builder()
-        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename))
-        .body(body)
-        .unwrap())
+    Ok(Json(entries))
 }
 
-use async_zip::tokio::write::ZipFileWriter;
-use async_zip::{Compression, ZipEntryBuilder};
-use tokio::io::duplex;
+#[derive(Serialize)]
+struct ZipListEntry {
+    /// Entry name inside the archive
+    name: String,
+    /// Path to fetch the file on its own from /download
+    path: String,
+    size: u64,
+}
 
-async fn zip_folder_handler(
+// The files /zip/folder/*path will contain, in archive order. A client whose
+// zip download broke off can fetch the files it didn't get yet one by one.
+async fn zip_list_handler(
     State(state): State<AppState>,
     Path(path): Path<String>,
-) -> Result<Response, (StatusCode, String)> {
-     if path.contains("..") {
-        return Err((StatusCode::FORBIDDEN, "Invalid path".to_string()));
-    }
-
-    let target_path = {
-        let folders = state.shared_folders.lock().unwrap();
-        resolve_path(&folders, &path)
-    };
-    
-    let target_path = target_path.ok_or((StatusCode::NOT_FOUND, "Path not found".to_string()))?;
+    Query(query): Query<ZipQuery>,
+    access: ShareAccess,
+) -> Result<Json<Vec<ZipListEntry>>, ApiError> {
+    let target_path = resolve_dir(&state, &access, &path)?;
+    let exclude = query.exclude()?;
+    let zip_name = zip_folder_name(&path);
+    let prefix = if query.flatten { String::new() } else { zip_name };
 
-     if !target_path.exists() || !target_path.is_dir() {
-         return Err((StatusCode::NOT_FOUND, "Folder not found".to_string()));
-    }
+    let ctx = state.zip_context(Compression::Stored, None, exclude);
+    let entries = archived_files(&target_path, &ctx)
+        .await
+        .files
+        .into_iter()
+        .map(|file| ZipListEntry {
+            name: zip_entry_name(&prefix, &target_path, &file.path),
+            path: zip_entry_name(path.trim_matches('/'), &target_path, &file.path),
+            size: file.size,
+        })
+        .collect();
+    Ok(Json(entries))
+}
 
-    let (w, r) = duplex(64 * 1024);
-    let stream = ReaderStream::new(r);
-    let body = Body::from_stream(stream);
+struct ArchivedFile {
+    path: PathBuf,
+    size: u64,
+    modified: Option<SystemTime>,
+}
 
-    let target_path_clone = target_path.clone();
-    let parent_path = target_path.parent().unwrap_or(&target_path).to_path_buf();
+struct ArchivedFiles {
+    files: Vec<ArchivedFile>,
+    // False when the archive holds more than these files: a limit stopped
+    // it early or something couldn't be read, and an errors manifest follows
+    complete: bool,
+}
 
-    tokio::spawn(async move {
-        let mut writer = ZipFileWriter::with_tokio(w);
-        let mut stack = vec![target_path_clone];
-        
-        while let Some(current_dir) = stack.pop() {
-            let mut entries = match tokio::fs::read_dir(&current_dir).await {
-                Ok(e) => e,
-                Err(_) => continue,
+// Files a folder archive written with `ctx` will contain, taking the walk and
+// the checks of archive_dir
+async fn archived_files(dir: &std::path::Path, ctx: &ZipContext<'_>) -> ArchivedFiles {
+    let limits = &ctx.limits;
+    let mut archived = ArchivedFiles { files: Vec::new(), complete: true };
+    let mut bytes: u64 = 0;
+    let mut walk = ArchiveWalk::new(dir, ctx);
+    while let Some(step) = walk.next().await {
+        let files = match step {
+            WalkStep::Files(files) => files,
+            WalkStep::Unreadable(..) => {
+                archived.complete = false;
+                continue;
+            }
+            WalkStep::TooDeep(_) => {
+                archived.complete = false;
+                break;
+            }
+        };
+        for path in files {
+            // zip_file skips files it can't open, so leave them out here too
+            let meta = match File::open(&path).await {
+                Ok(file) => file.metadata().await,
+                Err(e) => Err(e),
             };
-
-            while let Ok(Some(entry)) = entries.next_entry().await {
-                let path = entry.path();
-                if path.is_dir() {
-                    stack.push(path.clone());
-                    // Ideally we add empty dirs too, but for file download mostly files matter
-                } else {
-                    let relative_path = path.strip_prefix(&parent_path).unwrap_or(&path);
-                    let filename = relative_path.to_string_lossy().into_owned();
-                    
-                    let builder = ZipEntryBuilder::new(filename.into(), Compression::Deflate);
-                    
-                    if let Ok(mut file) = File::open(&path).await {
-                         if let Ok(entry_writer) = writer.write_entry_stream(builder).await {
-                             let mut compat_writer = entry_writer.compat_write();
-                             let _ = tokio::io::copy(&mut file, &mut compat_writer).await;
-                             let _ = compat_writer.into_inner().close().await;
-                         }
-                    }
-                }
+            let Ok(meta) = meta else {
+                archived.complete = false;
+                continue;
+            };
+            // ZipContext::reserve ends the archive here
+            if archived.files.len() >= limits.max_entries || bytes.saturating_add(meta.len()) > limits.max_bytes {
+                archived.complete = false;
+                return archived;
             }
+            bytes += meta.len();
+            archived.files.push(ArchivedFile { path, size: meta.len(), modified: meta.modified().ok() });
         }
-        let _ = writer.close().await;
-    });
-
-    let zip_name = target_path.file_name().unwrap_or_default().to_string_lossy().to_string();
-
-    Ok(Response::builder()
-        .header(header::CONTENT_TYPE, "application/zip")
-        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.zip\"", zip_name))
-        .body(body)
-        .unwrap())
+    }
+    archived
 }
 
 #[derive(Deserialize)]
 struct SelectionRequest {
     files: Vec<String>,
+    /// Name entries relative to each selected item instead of by their full path
+    #[serde(default)]
+    flatten: bool,
+    /// Globs of files and folders to leave out, see `is_excluded`
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+impl SelectionRequest {
+    // The whole selection is refused if any of it is in a locked share
+    fn check_access(&self, access: &ShareAccess, shared_folders: &[SharedFolder]) -> Result<(), ApiError> {
+        for file in &self.files {
+            access.resolve(shared_folders, file)?;
+        }
+        Ok(())
+    }
 }
 
 async fn zip_selection_handler(
     State(state): State<AppState>,
+    access: ShareAccess,
     Json(payload): Json<SelectionRequest>,
-) -> Result<Response, (StatusCode, String)> {
-    let (w, r) = duplex(64 * 1024);
-    let stream = ReaderStream::new(r);
+) -> Result<Response, ApiError> {
+    let (w, r) = duplex(state.options.zip_buffer_size());
+    let stream = ReaderStream::new(CountedReader::new(r, &state, true));
     let body = Body::from_stream(stream);
     
-    let shared_folders = state.shared_folders.lock().unwrap().clone();
-    
+    let shared_folders = state.roots();
+    let exclude = exclude_globs(payload.exclude.iter().map(String::as_str))?;
+    payload.check_access(&access, &shared_folders)?;
+    let mut ctx = state.zip_context(Compression::Deflate, None, exclude);
+
     tokio::spawn(async move {
         let mut writer = ZipFileWriter::with_tokio(w);
-        
-        for rel_path in payload.files {
-           if rel_path.contains("..") { continue; }
-           
-           if let Some(full_path) = resolve_path(&shared_folders, &rel_path) {
-                if full_path.is_file() {
-                    let filename = rel_path.clone(); // Use the relative path requested as name in zip
-                    let builder = ZipEntryBuilder::new(filename.into(), Compression::Deflate);
-                    
-                    if let Ok(mut file) = File::open(&full_path).await {
-                         if let Ok(entry_writer) = writer.write_entry_stream(builder).await {
-                             let mut compat_writer = entry_writer.compat_write();
-                             let _ = tokio::io::copy(&mut file, &mut compat_writer).await;
-                             let _ = compat_writer.into_inner().close().await;
-                         }
-                    }
-                } else if full_path.is_dir() {
-                    // Start recursive add
-                     let parent_path = full_path.parent().unwrap_or(&full_path).to_path_buf();
-                      let mut stack = vec![full_path.clone()];
-                        while let Some(current_dir) = stack.pop() {
-                            let mut entries = match tokio::fs::read_dir(&current_dir).await {
-                                Ok(e) => e,
-                                Err(_) => continue,
-                            };
-                            while let Ok(Some(entry)) = entries.next_entry().await {
-                                let path = entry.path();
-                                if path.is_dir() {
-                                    stack.push(path.clone());
-                                } else {
-                                    // Make relative to the PARENT of the selected folder so "FolderA/file" structure is kept?
-                                    // Or relative to the root of zip?
-                                    // If I selected "FolderA", I expect "FolderA/file" in zip.
-                                    // "FolderA" path in `resolve_path` is based on shared root.
-                                    // We need to map `full_path` -> `rel_path` + (path - full_path).
-                                    // This is tricky. Simplified: Just flatten or use relative to shared root?
-                                    // Just using keys provided in request seems reasonably safe if we resolve them.
-                                    // But we need to recurse.
-                                    // Let's stick to files for selection MVP or simple recursion.
-                                    // Actually, if we support picking folders in "Selection", we need deep zip.
-                                    
-                                    // Better approach for selection: Just zip file-by-file.
-                                    // If folder is in selection, we walk it.
-                                    // Relative path in ZIP should be `rel_path` / `subpath`.
-                                    let sub_rel = path.strip_prefix(&full_path).unwrap_or(&path);
-                                    let zip_entry_name = format!("{}/{}", rel_path, sub_rel.to_string_lossy());
-
-                                    let builder = ZipEntryBuilder::new(zip_entry_name.into(), Compression::Deflate);
-                                     if let Ok(mut file) = File::open(&path).await {
-                                         if let Ok(entry_writer) = writer.write_entry_stream(builder).await {
-                                             let mut compat_writer = entry_writer.compat_write();
-                                             let _ = tokio::io::copy(&mut file, &mut compat_writer).await;
-                                             let _ = compat_writer.into_inner().close().await;
-                                         }
-                                    }
-                                }
-                            }
-                        }
-                }
-           }
-        }
-        
-        let _ = writer.close().await;
+        let result = archive_selection(&mut writer, &shared_folders, payload, &mut ctx).await;
+        finish_archive(writer, ctx, result).await;
     });
 
     Ok(Response::builder()
         .header(header::CONTENT_TYPE, "application/zip")
-        .header(header::CONTENT_DISPOSITION, "attachment; filename=\"download.zip\"")
+        .header(header::CONTENT_DISPOSITION, attachment("download.zip"))
+        .body(body)
+        .unwrap())
+}
+
+// Same selection as `zip_selection_handler` as an uncompressed tar stream,
+// which clients can extract while it arrives
+async fn tar_selection_handler(
+    State(state): State<AppState>,
+    access: ShareAccess,
+    Json(payload): Json<SelectionRequest>,
+) -> Result<Response, ApiError> {
+    let (w, r) = duplex(state.options.zip_buffer_size());
+    let stream = ReaderStream::new(CountedReader::new(r, &state, true));
+    let body = Body::from_stream(stream);
+
+    let shared_folders = state.roots();
+    let exclude = exclude_globs(payload.exclude.iter().map(String::as_str))?;
+    payload.check_access(&access, &shared_folders)?;
+    let mut ctx = state.zip_context(Compression::Stored, None, exclude);
+
+    tokio::spawn(async move {
+        let mut writer = tokio_tar::Builder::new(w);
+        let result = archive_selection(&mut writer, &shared_folders, payload, &mut ctx).await;
+        finish_archive(writer, ctx, result).await;
+    });
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-tar")
+        .header(header::CONTENT_DISPOSITION, attachment("download.tar"))
         .body(body)
         .unwrap())
 }
 
-async fn root_handler() -> Html<&'static str> {
-    Html(r##"
+// Add every selected file and folder to the archive
+async fn archive_selection(
+    writer: &mut impl ArchiveWriter,
+    shared_folders: &[SharedFolder],
+    selection: SelectionRequest,
+    ctx: &mut ZipContext<'_>,
+) -> Result<(), ZipAborted> {
+    for rel_path in selection.files {
+        if rel_path.contains("..") { continue; }
+
+        let Some(full_path) = resolve_path(shared_folders, &rel_path) else {
+            ctx.fail(rel_path, "Not found");
+            continue;
+        };
+        if full_path.file_name().is_some_and(|name| ctx.exclude.is_match(name)) {
+            continue;
+        }
+        if full_path.is_file() && !ctx.permits(&full_path) {
+            ctx.fail(rel_path, "File type not allowed");
+            continue;
+        }
+        if full_path.is_file() && ctx.withholds(&full_path) {
+            ctx.fail(rel_path, "Has a download limit, download it on its own");
+            continue;
+        }
+
+        // Entries keep the relative path that was requested, so selecting
+        // "FolderA" yields "FolderA/..." in the archive. Flattened, a file
+        // is stored under its own name and a folder's contents at the top.
+        let prefix = if selection.flatten { String::new() } else { rel_path.clone() };
+        if full_path.is_file() {
+            let name = if selection.flatten {
+                full_path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+            } else {
+                rel_path
+            };
+            writer.add_file(EntrySource::Disk(&full_path), name, ctx).await?;
+        } else if full_path.is_dir() {
+            archive_dir(writer, &full_path, &prefix, ctx).await?;
+        } else {
+            ctx.fail(rel_path, "Not found");
+        }
+    }
+    Ok(())
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+const FAVICON_SVG: &str = r#"<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 512 512'><defs><linearGradient id='bg' x1='0%' y1='0%' x2='100%' y2='100%'><stop offset='0%' style='stop-color:#3B82F6'/><stop offset='100%' style='stop-color:#4F46E5'/></linearGradient></defs><rect width='512' height='512' rx='96' fill='url(#bg)'/><g fill='none' stroke='white' stroke-width='24' stroke-linecap='round' stroke-linejoin='round'><circle cx='256' cy='172' r='40' fill='white'/><circle cx='160' cy='340' r='40' fill='white'/><circle cx='352' cy='340' r='40' fill='white'/><line x1='256' y1='212' x2='180' y2='305'/><line x1='256' y1='212' x2='332' y2='305'/><line x1='200' y1='340' x2='312' y2='340'/></g></svg>"#;
+
+// The configured icon if it can be read, the built-in one otherwise
+async fn favicon_handler(State(state): State<AppState>) -> Response {
+    if let Some(icon_path) = state.options.icon_path.as_deref() {
+        let icon_path = std::path::Path::new(icon_path);
+        match tokio::fs::read(icon_path).await {
+            Ok(bytes) => {
+                return Response::builder()
+                    .header(header::CONTENT_TYPE, content_type(icon_path))
+                    .header(header::CACHE_CONTROL, "public, max-age=3600")
+                    .body(Body::from(bytes))
+                    .unwrap();
+            }
+            Err(e) => log::warn!("Failed to read icon {}: {}", icon_path.display(), e),
+        }
+    }
+    Response::builder()
+        .header(header::CONTENT_TYPE, "image/svg+xml")
+        .header(header::CACHE_CONTROL, "public, max-age=3600")
+        .body(Body::from(FAVICON_SVG))
+        .unwrap()
+}
+
+// Static hosting for shared folders when serve_index is on, e.g. /site/Site/
+// renders Site/index.html and its relative links resolve to files under it.
+// Kept under /site so everything else still reaches the fallback or ui_dir.
+async fn site_handler(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    access: ShareAccess,
+    uri: axum::http::Uri,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if path.contains("..") {
+        return Err(ApiError::forbidden("Invalid path"));
+    }
+
+    let target = {
+        let folders = state.roots();
+        access.resolve(&folders, &path)?
+    };
+    let mut target = target.ok_or_else(|| ApiError::not_found("Not found"))?;
+
+    if target.is_dir() {
+        // Without the trailing slash the page's relative links would miss the folder
+        if !uri.path().ends_with('/') {
+            return Ok(Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, format!("{}/", uri.path()))
+                .body(Body::empty())
+                .unwrap());
+        }
+        target.push("index.html");
+    }
+    if is_special_file(&target) {
+        return Err(ApiError::forbidden("Not a regular file"));
+    }
+    // Also a folder without an index.html
+    if !target.is_file() {
+        return Err(ApiError::not_found("Not found"));
+    }
+    state.check_file_type(&target)?;
+    let slot = state.reserve_download(&target)?;
+
+    let mut response = Response::builder().header(header::CONTENT_TYPE, content_type(&target));
+    let variant = if state.options.precompressed {
+        response = response.header(header::VARY, "accept-encoding");
+        precompressed_variant(&target, &headers).await
+    } else {
+        None
+    };
+    let file = match variant {
+        Some((variant, encoding)) => {
+            response = response.header(header::CONTENT_ENCODING, encoding);
+            variant
+        }
+        None => File::open(&target).await?,
+    };
+    // Pages are sent whole, so a limited one counts once it has been read out
+    let file = TrackedReader::new(file, move |_| {
+        if let Some(slot) = &slot {
+            slot.complete();
+        }
+    });
+    Ok(response
+        .body(Body::from_stream(ReaderStream::new(file)))
+        .unwrap())
+}
+
+// Answers every archive route when zip downloads are turned off
+async fn archives_disabled_handler() -> ApiError {
+    ApiError::forbidden("Zip downloads are disabled on this server")
+}
+
+// Stands in for the web client when serve_ui is off
+async fn api_only_handler() -> ApiError {
+    ApiError::not_found("The web UI is disabled on this server, see /api/info")
+}
+
+async fn root_handler(State(state): State<AppState>) -> Html<String> {
+    let server_name = escape_html(state.options.server_name());
+    // Escape "</" so the JSON can't close the surrounding <script> tag
+    let config = serde_json::to_string(&state.server_info())
+        .unwrap_or_else(|_| "{}".to_string())
+        .replace("</", "<\\/");
+    Html(
+        CLIENT_HTML
+            .replace("__HFS_SERVER_NAME__", &server_name)
+            .replace("__HFS_CONFIG__", &config),
+    )
+}
+
+// Self-contained so it renders even when the CDN assets of the client can't load
+const ERROR_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>__HFS_STATUS__ - __HFS_SERVER_NAME__</title>
+    <link rel="icon" href="/favicon.svg">
+    <style>
+        body { margin: 0; min-height: 100vh; display: flex; align-items: center; justify-content: center; background: #09090b; color: #e4e4e7; font-family: Inter, system-ui, sans-serif; }
+        main { text-align: center; padding: 2rem; }
+        .status { font-size: 4rem; font-weight: 700; color: #3f3f46; margin: 0; }
+        .message { font-size: 1.125rem; color: #a1a1aa; margin: 0.5rem 0 2rem; }
+        a { display: inline-block; padding: 0.625rem 1.25rem; border-radius: 0.75rem; background: #27272a; border: 1px solid #3f3f46; color: #60a5fa; text-decoration: none; font-weight: 500; }
+        a:hover { background: #3f3f46; }
+    </style>
+</head>
+<body>
+    <main>
+        <p class="status">__HFS_STATUS__</p>
+        <p class="message">__HFS_MESSAGE__</p>
+        <a href="/">Back to __HFS_SERVER_NAME__</a>
+    </main>
+</body>
+</html>
+"##;
+
+const CLIENT_HTML: &str = r##"
 <!DOCTYPE html>
 <html lang="en">
 <head>
     <meta charset="UTF-8">
     <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>HFS Client</title>
-    <link rel="icon" type="image/svg+xml" href="data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 512 512'%3E%3Cdefs%3E%3ClinearGradient id='bg' x1='0%25' y1='0%25' x2='100%25' y2='100%25'%3E%3Cstop offset='0%25' style='stop-color:%233B82F6'/%3E%3Cstop offset='100%25' style='stop-color:%234F46E5'/%3E%3C/linearGradient%3E%3C/defs%3E%3Crect width='512' height='512' rx='96' fill='url(%23bg)'/%3E%3Cg fill='none' stroke='white' stroke-width='24' stroke-linecap='round' stroke-linejoin='round'%3E%3Ccircle cx='256' cy='172' r='40' fill='white'/%3E%3Ccircle cx='160' cy='340' r='40' fill='white'/%3E%3Ccircle cx='352' cy='340' r='40' fill='white'/%3E%3Cline x1='256' y1='212' x2='180' y2='305'/%3E%3Cline x1='256' y1='212' x2='332' y2='305'/%3E%3Cline x1='200' y1='340' x2='312' y2='340'/%3E%3C/g%3E%3C/svg%3E">
+    <title>__HFS_SERVER_NAME__</title>
+    <link rel="icon" href="/favicon.svg">
     <link rel="preconnect" href="https://fonts.googleapis.com">
     <link rel="preconnect" href="https://fonts.gstatic.com" crossorigin>
     <link href="https://fonts.googleapis.com/css2?family=Inter:wght@400;500;600;700&display=swap" rel="stylesheet">
@@ -408,7 +4671,7 @@ async fn root_handler() -> Html<&'static str> {
                         <span class="absolute -bottom-0.5 -right-0.5 w-3 h-3 bg-emerald-500 border-2 border-zinc-900 rounded-full"></span>
                     </div>
                     <div>
-                        <h1 class="text-lg sm:text-xl font-bold text-white">HFS Client</h1>
+                        <h1 class="text-lg sm:text-xl font-bold text-white">__HFS_SERVER_NAME__</h1>
                         <p class="text-[10px] text-zinc-500 hidden sm:block">Secure Local File Transfer</p>
                     </div>
                 </div>
@@ -443,6 +4706,22 @@ async fn root_handler() -> Html<&'static str> {
 
                <!-- View Actions -->
                <div class="flex items-center gap-2 shrink-0">
+                   <button @click="toggleClipboard" :class="showClipboard ? 'bg-zinc-700 text-blue-400 border-zinc-600' : 'bg-zinc-800 text-zinc-500 hover:text-zinc-300 border-zinc-700'" class="p-2.5 rounded-xl border transition-all active:scale-95" title="Shared clipboard">
+                       <i data-lucide="clipboard" class="w-4 h-4"></i>
+                   </button>
+                   <button @click="testSpeed" :disabled="speedTesting" :class="speedTesting ? 'text-blue-400 animate-pulse' : 'text-zinc-500 hover:text-zinc-300'" class="p-2.5 rounded-xl border bg-zinc-800 border-zinc-700 transition-all active:scale-95" title="Test speed">
+                       <i data-lucide="gauge" class="w-4 h-4"></i>
+                   </button>
+                   <div v-if="viewMode === 'grid'" class="flex items-center bg-zinc-800 rounded-xl p-1 border border-zinc-700">
+                        <select v-model="sortKey" @change="setSort(sortKey, sortOrder)" class="bg-transparent text-xs text-zinc-300 pl-2 pr-1 py-1.5 focus:outline-none">
+                            <option value="name">Name</option>
+                            <option value="size">Size</option>
+                            <option value="modified">Modified</option>
+                        </select>
+                        <button @click="setSort(sortKey, sortOrder === 'asc' ? 'desc' : 'asc')" class="px-2 py-1 rounded-lg text-xs text-zinc-400 hover:text-zinc-200 transition-all active:scale-95" :title="sortOrder === 'asc' ? 'Ascending' : 'Descending'">
+                            {{ sortOrder === 'asc' ? '▲' : '▼' }}
+                        </button>
+                   </div>
                    <div class="flex bg-zinc-800 rounded-xl p-1 border border-zinc-700">
                         <button @click="viewMode = 'grid'" :class="{'bg-zinc-700 shadow-sm text-blue-400': viewMode === 'grid', 'text-zinc-500 hover:text-zinc-300': viewMode !== 'grid'}" class="p-2 rounded-lg transition-all active:scale-95">
                             <i data-lucide="layout-grid" class="w-4 h-4"></i>
@@ -454,6 +4733,24 @@ async fn root_handler() -> Html<&'static str> {
                </div>
             </div>
 
+            <!-- Clipboard Panel -->
+            <div v-if="showClipboard" class="bg-zinc-900/50 border-x border-t border-zinc-800 p-3 sm:p-4 space-y-3">
+                <div class="flex flex-col sm:flex-row gap-2">
+                    <textarea v-model="clipText" rows="2" placeholder="Paste text to share with other devices..." class="flex-1 bg-zinc-950 border border-zinc-800 rounded-xl px-3 py-2 text-sm text-zinc-300 placeholder:text-zinc-600 focus:outline-none focus:border-blue-500/50 resize-none"></textarea>
+                    <button @click="shareClip" :disabled="!clipText.trim()" class="bg-gradient-to-r from-blue-600 to-indigo-600 hover:from-blue-500 hover:to-indigo-500 disabled:opacity-50 text-white px-4 py-2 rounded-xl text-sm font-semibold flex items-center justify-center gap-2 transition-all active:scale-95">
+                        <i data-lucide="send" class="w-4 h-4"></i>
+                        Share
+                    </button>
+                </div>
+                <p v-if="clipError" class="text-xs text-red-400">{{ clipError }}</p>
+                <div v-for="(snippet, index) in snippets" :key="snippet.timestamp + '-' + index" class="flex items-start gap-2 bg-zinc-950/60 border border-zinc-800 rounded-xl px-3 py-2">
+                    <pre class="flex-1 text-xs text-zinc-300 whitespace-pre-wrap break-all font-mono max-h-32 overflow-y-auto">{{ snippet.text }}</pre>
+                    <button @click="copyText(snippet.text)" class="p-1.5 rounded-lg text-zinc-500 hover:text-blue-400 hover:bg-zinc-800 transition-colors shrink-0" title="Copy">
+                        <i data-lucide="copy" class="w-3.5 h-3.5"></i>
+                    </button>
+                </div>
+            </div>
+
             <!-- Selection Toolbar -->
             <div v-if="selectedItems.length > 0" class="bg-blue-500/10 border-x border-blue-500/20 px-4 py-3 flex flex-wrap items-center justify-between gap-3">
                  <div class="text-sm text-blue-400 font-medium flex items-center gap-2">
@@ -466,7 +4763,7 @@ async fn root_handler() -> Html<&'static str> {
                      <button @click="clearSelection" class="px-3 py-1.5 rounded-lg text-sm font-medium text-zinc-400 hover:bg-zinc-800 transition-colors active:scale-95">
                          Clear
                      </button>
-                     <button @click="downloadSelection" class="bg-gradient-to-r from-blue-600 to-indigo-600 hover:from-blue-500 hover:to-indigo-500 text-white px-4 py-1.5 rounded-lg text-sm font-semibold flex items-center gap-2 transition-all shadow-lg shadow-blue-500/25 active:scale-95">
+                     <button v-if="config.allow_zip !== false" @click="downloadSelection" class="bg-gradient-to-r from-blue-600 to-indigo-600 hover:from-blue-500 hover:to-indigo-500 text-white px-4 py-1.5 rounded-lg text-sm font-semibold flex items-center gap-2 transition-all shadow-lg shadow-blue-500/25 active:scale-95">
                          <i data-lucide="download" class="w-4 h-4"></i>
                          <span class="hidden sm:inline">Download as Zip</span>
                          <span class="sm:hidden">Download</span>
@@ -485,6 +4782,24 @@ async fn root_handler() -> Html<&'static str> {
                         <p class="text-zinc-500 text-sm">Loading files...</p>
                     </div>
 
+                    <!-- Error -->
+                    <div v-else-if="errorMessage" class="h-64 flex flex-col items-center justify-center text-zinc-500">
+                        <div class="w-20 h-20 rounded-full bg-red-500/10 flex items-center justify-center mb-4">
+                            <i data-lucide="alert-triangle" class="w-10 h-10 text-red-400"></i>
+                        </div>
+                        <p class="font-medium text-zinc-400">{{ errorMessage }}</p>
+                        <button @click="navigate('/')" class="text-sm mt-2 text-blue-400 hover:text-blue-300">Back to shared folders</button>
+                    </div>
+
+                    <!-- Nothing shared -->
+                    <div v-else-if="items.length === 0 && config.share_count === 0 && currentPath === '/'" class="h-64 flex flex-col items-center justify-center text-zinc-500">
+                        <div class="w-20 h-20 rounded-full bg-zinc-800 flex items-center justify-center mb-4">
+                            <i data-lucide="folder-x" class="w-10 h-10 text-zinc-600"></i>
+                        </div>
+                        <p class="font-medium text-zinc-400">No folders are being shared</p>
+                        <p class="text-sm mt-1">Add some in the HFS app on the host computer</p>
+                    </div>
+
                     <!-- Empty -->
                     <div v-else-if="items.length === 0" class="h-64 flex flex-col items-center justify-center text-zinc-500">
                         <div class="w-20 h-20 rounded-full bg-zinc-800 flex items-center justify-center mb-4">
@@ -497,10 +4812,10 @@ async fn root_handler() -> Html<&'static str> {
                     <!-- Grid View -->
                     <div v-else-if="viewMode === 'grid'" class="grid grid-cols-2 sm:grid-cols-3 md:grid-cols-4 lg:grid-cols-5 xl:grid-cols-6 gap-3 sm:gap-4 relative">
                         <TransitionGroup name="list">
-                        <div v-for="item in items" :key="item.path" 
-                             @click.exact="toggleSelect(item)"
+                        <div v-for="(item, index) in items" :key="item.path" :data-index="index"
+                             @click="onItemClick(index, $event)"
                              @dblclick="handleOpen(item)"
-                             :class="{'ring-2 ring-blue-500 bg-blue-500/10': isSelected(item), 'hover:bg-zinc-800/50': !isSelected(item)}"
+                             :class="{'ring-2 ring-blue-500 bg-blue-500/10': isSelected(item), 'hover:bg-zinc-800/50': !isSelected(item), 'ring-1 ring-zinc-500': focusIndex === index && !isSelected(item)}"
                              class="group relative p-3 sm:p-4 rounded-xl border border-zinc-800 cursor-pointer transition-all duration-200 flex flex-col items-center text-center select-none hover:border-zinc-700">
                             
                             <!-- Checkbox -->
@@ -511,7 +4826,10 @@ async fn root_handler() -> Html<&'static str> {
                             </div>
 
                             <!-- Download Quick Action -->
-                            <button @click.stop="downloadItem(item)" class="absolute top-2 right-2 w-8 h-8 bg-zinc-800/90 backdrop-blur rounded-lg border border-zinc-700 flex items-center justify-center text-zinc-400 hover:text-blue-400 hover:border-blue-500/50 opacity-0 group-hover:opacity-100 transition-all z-10 active:scale-95">
+                            <button v-if="!item.is_dir" @click.stop="copyLink(item)" class="absolute top-2 right-11 w-8 h-8 bg-zinc-800/90 backdrop-blur rounded-lg border border-zinc-700 flex items-center justify-center text-zinc-400 hover:text-blue-400 hover:border-blue-500/50 opacity-0 group-hover:opacity-100 transition-all z-10 active:scale-95" title="Copy direct link">
+                                <i data-lucide="link" class="w-4 h-4"></i>
+                            </button>
+                            <button v-if="canDownload(item)" @click.stop="downloadItem(item)" class="absolute top-2 right-2 w-8 h-8 bg-zinc-800/90 backdrop-blur rounded-lg border border-zinc-700 flex items-center justify-center text-zinc-400 hover:text-blue-400 hover:border-blue-500/50 opacity-0 group-hover:opacity-100 transition-all z-10 active:scale-95">
                                 <i data-lucide="download" class="w-4 h-4"></i>
                             </button>
 
@@ -521,7 +4839,7 @@ async fn root_handler() -> Html<&'static str> {
                                     <i data-lucide="folder" class="w-14 h-14 sm:w-16 sm:h-16 text-amber-400 fill-amber-400/20"></i>
                                 </div>
                                 <div v-else class="w-12 h-14 sm:w-14 sm:h-16 relative flex items-center justify-center">
-                                    <i data-lucide="file" class="w-12 h-14 sm:w-14 sm:h-16 text-zinc-500"></i>
+                                    <i :data-lucide="kindIcon(item.kind)" class="w-12 h-14 sm:w-14 sm:h-16 text-zinc-500"></i>
                                     <span class="absolute bottom-3 text-[8px] sm:text-[9px] font-bold text-zinc-400 uppercase">{{ getExt(item.name) }}</span>
                                 </div>
                                 </TransitionGroup>
@@ -529,6 +4847,8 @@ async fn root_handler() -> Html<&'static str> {
                             
                             <!-- Name & Size -->
                             <div class="text-xs sm:text-sm font-medium text-zinc-300 truncate w-full px-1" :title="item.name">{{ item.name }}</div>
+                            <div v-if="item.available === false" class="text-[10px] text-red-400 mt-0.5">Unavailable</div>
+                            <div v-if="item.protected" class="text-[10px] text-amber-400 mt-0.5">Password protected</div>
                             <div class="text-[10px] sm:text-xs text-zinc-500 mt-1">{{ formatSize(item.size) }}</div>
                         </div>
                     </div>
@@ -538,18 +4858,25 @@ async fn root_handler() -> Html<&'static str> {
                         <!-- Header -->
                         <div class="hidden sm:grid grid-cols-12 gap-4 px-4 py-2 text-xs font-semibold text-zinc-500 border-b border-zinc-800 uppercase tracking-wider sticky top-0 bg-zinc-900/90 backdrop-blur z-10">
                             <div class="col-span-1"></div>
-                            <div class="col-span-6">Name</div>
-                            <div class="col-span-2 text-right">Size</div>
-                            <div class="col-span-3 text-right">Actions</div>
+                            <button @click="toggleSort('name')" class="col-span-5 text-left uppercase tracking-wider hover:text-zinc-300 transition-colors">
+                                Name <span v-if="sortKey === 'name'" class="text-blue-400">{{ sortOrder === 'asc' ? '▲' : '▼' }}</span>
+                            </button>
+                            <button @click="toggleSort('size')" class="col-span-2 text-right uppercase tracking-wider hover:text-zinc-300 transition-colors">
+                                Size <span v-if="sortKey === 'size'" class="text-blue-400">{{ sortOrder === 'asc' ? '▲' : '▼' }}</span>
+                            </button>
+                            <button @click="toggleSort('modified')" class="col-span-2 text-right uppercase tracking-wider hover:text-zinc-300 transition-colors">
+                                Modified <span v-if="sortKey === 'modified'" class="text-blue-400">{{ sortOrder === 'asc' ? '▲' : '▼' }}</span>
+                            </button>
+                            <div class="col-span-2 text-right">Actions</div>
                         </div>
                         
                         <!-- Items -->
                         <div class="relative">
                         <TransitionGroup name="list">
-                        <div v-for="item in items" :key="item.path"
-                             @click.exact="toggleSelect(item)"
+                        <div v-for="(item, index) in items" :key="item.path" :data-index="index"
+                             @click="onItemClick(index, $event)"
                              @dblclick="handleOpen(item)"
-                             :class="{'bg-blue-500/10': isSelected(item), 'hover:bg-zinc-800/50': !isSelected(item)}"
+                             :class="{'bg-blue-500/10': isSelected(item), 'hover:bg-zinc-800/50': !isSelected(item), 'ring-1 ring-inset ring-zinc-500': focusIndex === index}"
                              class="grid grid-cols-12 gap-2 sm:gap-4 items-center px-2 sm:px-4 py-3 sm:py-4 border-b border-zinc-800/50 cursor-pointer transition-colors text-sm">
                             
                             <!-- Checkbox -->
@@ -560,24 +4887,34 @@ async fn root_handler() -> Html<&'static str> {
                             </div>
                             
                             <!-- Name -->
-                            <div class="col-span-7 sm:col-span-6 flex items-center gap-2 sm:gap-3 min-w-0">
+                            <div class="col-span-7 sm:col-span-5 flex items-center gap-2 sm:gap-3 min-w-0">
                                 <div v-if="item.is_dir" class="w-8 h-8 sm:w-10 sm:h-10 shrink-0 flex items-center justify-center">
                                     <i data-lucide="folder" class="w-8 h-8 sm:w-10 sm:h-10 text-amber-400 fill-amber-400/20"></i>
                                 </div>
                                 <div v-else class="w-8 h-8 sm:w-10 sm:h-10 rounded-lg bg-zinc-800 flex items-center justify-center shrink-0">
-                                    <i data-lucide="file" class="w-4 h-4 sm:w-5 sm:h-5 text-zinc-500"></i>
+                                    <i :data-lucide="kindIcon(item.kind)" class="w-4 h-4 sm:w-5 sm:h-5 text-zinc-500"></i>
                                 </div>
                                 <span class="truncate font-medium text-zinc-300">{{ item.name }}</span>
+                                <span v-if="item.available === false" class="text-[10px] px-1.5 py-0.5 rounded bg-red-500/10 text-red-400 border border-red-500/20 shrink-0">Unavailable</span>
+                                <span v-if="item.protected" class="text-[10px] px-1.5 py-0.5 rounded bg-amber-500/10 text-amber-400 border border-amber-500/20 shrink-0">Password</span>
                             </div>
                             
                             <!-- Size -->
                             <div class="col-span-2 text-right font-mono text-xs text-zinc-500 hidden sm:block">
                                 {{ formatSize(item.size) }}
                             </div>
+
+                            <!-- Modified -->
+                            <div class="col-span-2 text-right text-xs text-zinc-500 hidden sm:block">
+                                {{ formatDate(item.modified) }}
+                            </div>
                             
                             <!-- Actions -->
-                            <div class="col-span-4 sm:col-span-3 flex justify-end gap-1">
-                                <button @click.stop="downloadItem(item)" class="px-2 sm:px-3 py-1.5 bg-blue-500/10 hover:bg-blue-500/20 text-blue-400 rounded-lg text-xs font-medium flex items-center gap-1.5 transition-colors active:scale-95 border border-blue-500/20">
+                            <div class="col-span-4 sm:col-span-2 flex justify-end gap-1">
+                                <button v-if="!item.is_dir" @click.stop="copyLink(item)" class="p-1.5 rounded-lg text-zinc-500 hover:text-blue-400 hover:bg-zinc-800 transition-colors active:scale-95" title="Copy direct link">
+                                    <i data-lucide="link" class="w-3.5 h-3.5"></i>
+                                </button>
+                                <button v-if="canDownload(item)" @click.stop="downloadItem(item)" class="px-2 sm:px-3 py-1.5 bg-blue-500/10 hover:bg-blue-500/20 text-blue-400 rounded-lg text-xs font-medium flex items-center gap-1.5 transition-colors active:scale-95 border border-blue-500/20">
                                     <i data-lucide="download" class="w-3.5 h-3.5"></i>
                                     <span class="hidden sm:inline">{{ item.is_dir ? 'Zip' : 'Download' }}</span>
                                 </button>
@@ -587,6 +4924,23 @@ async fn root_handler() -> Html<&'static str> {
                 </div>
             </div>
         </main>
+
+        <!-- Toast -->
+        <div v-if="toast" class="fixed bottom-4 left-1/2 -translate-x-1/2 bg-zinc-800 border border-zinc-700 text-zinc-200 text-sm px-4 py-2 rounded-xl shadow-xl z-50">
+            {{ toast }}
+        </div>
+
+        <!-- Zip Progress -->
+        <div v-if="zipProgress" class="fixed bottom-4 right-4 left-4 sm:left-auto sm:w-80 bg-zinc-900 border border-zinc-800 rounded-2xl shadow-xl p-4 z-50">
+            <div class="flex items-center justify-between gap-3 text-sm">
+                <span class="font-medium text-zinc-200 truncate">{{ zipProgress.done ? 'Zipped' : 'Zipping' }} {{ zipProgress.name }}</span>
+                <span class="text-xs text-zinc-500 shrink-0">{{ formatSize(zipProgress.processed) }} / {{ formatSize(zipProgress.total) }}</span>
+            </div>
+            <div class="mt-3 h-1.5 bg-zinc-800 rounded-full overflow-hidden">
+                <div class="h-full bg-gradient-to-r from-blue-600 to-indigo-600 transition-all" :style="{ width: (zipProgress.total ? Math.min(100, zipProgress.processed / zipProgress.total * 100) : 0) + '%' }"></div>
+            </div>
+            <p v-if="zipProgress.current_file && !zipProgress.done" class="mt-2 text-xs text-zinc-500 truncate">{{ zipProgress.current_file }}</p>
+        </div>
         
         <!-- Footer -->
         <footer class="bg-zinc-900/50 border-t border-zinc-800 py-4 text-center">
@@ -594,17 +4948,32 @@ async fn root_handler() -> Html<&'static str> {
         </footer>
     </div>
 
+    <script>window.HFS_CONFIG = __HFS_CONFIG__</script>
     <script>
         const { createApp, ref, computed, onMounted } = Vue
         
         createApp({
             setup() {
+                const config = window.HFS_CONFIG || {}
                 const items = ref([])
                 const currentPath = ref('/')
                 const loading = ref(false)
                 const viewMode = ref('grid')
                 const selectedItems = ref([])
+                const zipProgress = ref(null)
+                const toast = ref('')
+                const speedTesting = ref(false)
+                const focusIndex = ref(-1)
+                // Start of a Shift range selection, set by the last plain click or Space
+                let anchorIndex = -1
                 const isConnected = ref(true)
+                const errorMessage = ref('')
+                const sortKey = ref(localStorage.getItem('hfs.sort') || 'name')
+                const sortOrder = ref(localStorage.getItem('hfs.order') || 'asc')
+                const showClipboard = ref(false)
+                const clipText = ref('')
+                const clipError = ref('')
+                const snippets = ref([])
 
                 const breadcrumbs = computed(() => {
                     const parts = currentPath.value.split('/').filter(p => p)
@@ -618,12 +4987,25 @@ async fn root_handler() -> Html<&'static str> {
                 async function fetchItems(path) {
                     loading.value = true
                     try {
-                        const res = await fetch(`/api/browse?path=${encodeURIComponent(path)}`)
-                        if (!res.ok) throw new Error('Network response was not ok')
+                        const params = new URLSearchParams({ path, sort: sortKey.value, order: sortOrder.value })
+                        const res = await fetch(`/api/browse?${params}`, { headers: passwordHeaders(path) })
+                        isConnected.value = true
+                        if (res.status === 401) {
+                            const message = await apiErrorMessage(res)
+                            if (askPassword(path, message)) return await fetchItems(path)
+                            errorMessage.value = message
+                            return
+                        }
+                        if (!res.ok) {
+                            errorMessage.value = await apiErrorMessage(res)
+                            return
+                        }
+                        errorMessage.value = ''
                         items.value = await res.json()
                         currentPath.value = path
                         selectedItems.value = []
-                        isConnected.value = true
+                        focusIndex.value = -1
+                        anchorIndex = -1
                     } catch (e) {
                         console.error(e)
                         isConnected.value = false
@@ -633,14 +5015,85 @@ async fn root_handler() -> Html<&'static str> {
                     }
                 }
 
+                // Passwords of protected shares, kept for this browser session
+                const sharePasswords = JSON.parse(sessionStorage.getItem('hfs-share-passwords') || '{}')
+
+                function shareName(path) {
+                    return path.replace(/^\/+/, '').split('/')[0]
+                }
+
+                function passwordHeaders(path) {
+                    const password = sharePasswords[shareName(path)]
+                    return password ? { 'X-Share-Password': password } : {}
+                }
+
+                // Links the browser follows itself can't carry headers
+                function withPassword(url, path) {
+                    const password = sharePasswords[shareName(path)]
+                    if (!password) return url
+                    return url + (url.includes('?') ? '&' : '?') + 'share_password=' + encodeURIComponent(password)
+                }
+
+                // Asks for a share's password after a 401, true if one was entered
+                function askPassword(path, message) {
+                    const password = prompt(`${message}\nPassword:`)
+                    if (!password) return false
+                    sharePasswords[shareName(path)] = password
+                    sessionStorage.setItem('hfs-share-passwords', JSON.stringify(sharePasswords))
+                    return true
+                }
+
+                // Errors come back as { error: { code, message } }
+                async function apiErrorMessage(res) {
+                    try {
+                        const body = await res.json()
+                        return body.error?.message || res.statusText
+                    } catch (e) {
+                        return res.statusText
+                    }
+                }
+
                 function checkConnection() {
                    fetch(window.location.href, { method: 'HEAD', cache: 'no-store' })
                        .then(res => isConnected.value = res.ok)
                        .catch(() => isConnected.value = false)
                 }
 
+                // The current folder lives in the URL hash (#/FolderA/Sub) so it survives reloads and can be shared
+                function pathToHash(path) {
+                    return '#/' + path.split('/').filter(p => p).map(encodeURIComponent).join('/')
+                }
+
+                function hashToPath() {
+                    const parts = window.location.hash.replace(/^#\/?/, '').split('/').filter(p => p)
+                    return '/' + parts.map(decodeURIComponent).join('/')
+                }
+
+                // Changing the hash adds one history entry and triggers the hashchange listener
                 function navigate(path) {
-                    fetchItems(path)
+                    const hash = pathToHash(path)
+                    if (window.location.hash === hash) {
+                        fetchItems(path)
+                    } else {
+                        window.location.hash = hash
+                    }
+                }
+
+                function setSort(key, order) {
+                    sortKey.value = key
+                    sortOrder.value = order
+                    localStorage.setItem('hfs.sort', key)
+                    localStorage.setItem('hfs.order', order)
+                    fetchItems(currentPath.value)
+                }
+
+                // Clicking the active column flips the order, another column starts ascending
+                function toggleSort(key) {
+                    if (sortKey.value === key) {
+                        setSort(key, sortOrder.value === 'asc' ? 'desc' : 'asc')
+                    } else {
+                        setSort(key, 'asc')
+                    }
                 }
 
                 function handleOpen(item) {
@@ -651,12 +5104,51 @@ async fn root_handler() -> Html<&'static str> {
                     }
                 }
 
+                // Folders only download as archives, which the server may not offer
+                function canDownload(item) {
+                    return !item.is_dir || config.allow_zip !== false
+                }
+
                 function downloadItem(item) {
                     if (item.is_dir) {
-                        window.location.href = `/zip/folder/${item.path}`
+                        downloadFolder(item)
                     } else {
-                        window.location.href = `/download/${item.path}`
+                        window.location.href = withPassword(`/download/${item.path}`, item.path)
+                    }
+                }
+
+                // Register a zip job first so progress can be followed while the archive streams
+                async function downloadFolder(item) {
+                    let job = null
+                    try {
+                        const res = await fetch('/zip/folder/start', {
+                            method: 'POST',
+                            headers: { 'Content-Type': 'application/json', ...passwordHeaders(item.path) },
+                            body: JSON.stringify({ path: item.path })
+                        })
+                        if (res.ok) job = await res.json()
+                    } catch (e) {
+                        console.error(e)
+                    }
+                    if (!job) {
+                        window.location.href = withPassword(`/zip/folder/${item.path}`, item.path)
+                        return
+                    }
+
+                    zipProgress.value = { name: item.name, processed: 0, total: job.total, current_file: '', done: false }
+                    const events = new EventSource(withPassword(`/zip/progress/${job.job}`, item.path))
+                    events.onmessage = (e) => {
+                        zipProgress.value = { name: item.name, ...JSON.parse(e.data) }
+                        if (zipProgress.value.done) {
+                            events.close()
+                            setTimeout(() => zipProgress.value = null, 2000)
+                        }
                     }
+                    events.onerror = () => {
+                        events.close()
+                        zipProgress.value = null
+                    }
+                    window.location.href = withPassword(`/zip/folder/${item.path}?job=${encodeURIComponent(job.job)}`, item.path)
                 }
 
                 function toggleSelect(item) {
@@ -676,12 +5168,93 @@ async fn root_handler() -> Html<&'static str> {
                     selectedItems.value = []
                 }
 
+                function selectRange(from, to) {
+                    const [start, end] = from < to ? [from, to] : [to, from]
+                    selectedItems.value = items.value.slice(start, end + 1).map(item => item.path)
+                }
+
+                function onItemClick(index, event) {
+                    focusIndex.value = index
+                    if (event.shiftKey && anchorIndex > -1) {
+                        selectRange(anchorIndex, index)
+                    } else {
+                        anchorIndex = index
+                        toggleSelect(items.value[index])
+                    }
+                }
+
+                // Number of items per row, so Up/Down move vertically in the grid
+                function gridColumns() {
+                    if (viewMode.value !== 'grid') return 1
+                    const cells = document.querySelectorAll('[data-index]')
+                    if (cells.length === 0) return 1
+                    const top = cells[0].offsetTop
+                    let columns = 0
+                    while (columns < cells.length && cells[columns].offsetTop === top) columns++
+                    return Math.max(columns, 1)
+                }
+
+                function moveFocus(delta, extend) {
+                    if (items.value.length === 0) return
+                    const current = focusIndex.value < 0 ? (delta > 0 ? -1 : items.value.length) : focusIndex.value
+                    const next = Math.min(Math.max(current + delta, 0), items.value.length - 1)
+                    if (extend) {
+                        if (anchorIndex < 0) anchorIndex = focusIndex.value < 0 ? next : focusIndex.value
+                        selectRange(anchorIndex, next)
+                    }
+                    focusIndex.value = next
+                    const el = document.querySelector(`[data-index="${next}"]`)
+                    if (el) el.scrollIntoView({ block: 'nearest' })
+                }
+
+                function onKeydown(event) {
+                    const tag = event.target.tagName
+                    if (tag === 'INPUT' || tag === 'TEXTAREA' || tag === 'SELECT') return
+                    const focused = items.value[focusIndex.value]
+                    switch (event.key) {
+                        case 'ArrowDown':
+                            moveFocus(gridColumns(), event.shiftKey)
+                            break
+                        case 'ArrowUp':
+                            moveFocus(-gridColumns(), event.shiftKey)
+                            break
+                        case 'ArrowRight':
+                            if (viewMode.value !== 'grid') return
+                            moveFocus(1, event.shiftKey)
+                            break
+                        case 'ArrowLeft':
+                            if (viewMode.value !== 'grid') return
+                            moveFocus(-1, event.shiftKey)
+                            break
+                        case 'Enter':
+                            if (!focused) return
+                            handleOpen(focused)
+                            break
+                        case ' ':
+                            if (!focused) return
+                            anchorIndex = focusIndex.value
+                            toggleSelect(focused)
+                            break
+                        case 'Escape':
+                            clearSelection()
+                            break
+                        case 'a':
+                        case 'A':
+                            if (!(event.ctrlKey || event.metaKey)) return
+                            selectedItems.value = items.value.map(item => item.path)
+                            break
+                        default:
+                            return
+                    }
+                    event.preventDefault()
+                }
+
                 async function downloadSelection() {
                     if (selectedItems.value.length === 0) return
                     
                     const res = await fetch('/zip/selection', {
                         method: 'POST',
-                        headers: { 'Content-Type': 'application/json' },
+                        headers: { 'Content-Type': 'application/json', ...passwordHeaders(selectedItems.value[0]) },
                         body: JSON.stringify({ files: selectedItems.value })
                     })
                     
@@ -697,6 +5270,110 @@ async fn root_handler() -> Html<&'static str> {
                     }
                 }
 
+                async function loadClipboard() {
+                    try {
+                        const res = await fetch('/api/clipboard')
+                        if (res.ok) snippets.value = await res.json()
+                    } catch (e) {
+                        console.error(e)
+                    } finally {
+                        setTimeout(() => lucide.createIcons(), 50)
+                    }
+                }
+
+                function toggleClipboard() {
+                    showClipboard.value = !showClipboard.value
+                    if (showClipboard.value) loadClipboard()
+                }
+
+                async function shareClip() {
+                    clipError.value = ''
+                    const res = await fetch('/api/clipboard', {
+                        method: 'POST',
+                        headers: { 'Content-Type': 'application/json' },
+                        body: JSON.stringify({ text: clipText.value })
+                    })
+                    if (res.ok) {
+                        snippets.value = await res.json()
+                        clipText.value = ''
+                        setTimeout(() => lucide.createIcons(), 50)
+                    } else {
+                        clipError.value = await apiErrorMessage(res)
+                    }
+                }
+
+                let toastTimer = null
+                function showToast(message, duration = 2000) {
+                    toast.value = message
+                    clearTimeout(toastTimer)
+                    toastTimer = setTimeout(() => toast.value = '', duration)
+                }
+
+                // Downloads are timed here, uploads by the server once the body is in
+                const SPEEDTEST_DOWNLOAD_BYTES = 20 * 1024 * 1024
+                const SPEEDTEST_UPLOAD_BYTES = 5 * 1024 * 1024
+                async function testSpeed() {
+                    speedTesting.value = true
+                    showToast('Testing speed...', 60000)
+                    try {
+                        const started = performance.now()
+                        const res = await fetch(`/api/speedtest?bytes=${SPEEDTEST_DOWNLOAD_BYTES}`, { cache: 'no-store' })
+                        if (!res.ok) throw new Error(await apiErrorMessage(res))
+                        const received = (await res.arrayBuffer()).byteLength
+                        const seconds = Math.max((performance.now() - started) / 1000, 0.001)
+                        const down = received * 8 / 1e6 / seconds
+
+                        const upload = await fetch('/api/speedtest', {
+                            method: 'POST',
+                            headers: { 'Content-Type': 'application/octet-stream' },
+                            body: new Uint8Array(SPEEDTEST_UPLOAD_BYTES)
+                        })
+                        if (!upload.ok) throw new Error(await apiErrorMessage(upload))
+                        const up = (await upload.json()).mbps
+                        showToast(`↓ ${down.toFixed(1)} Mbit/s · ↑ ${up.toFixed(1)} Mbit/s`, 8000)
+                    } catch (e) {
+                        showToast('Speed test failed: ' + e.message, 5000)
+                    } finally {
+                        speedTesting.value = false
+                    }
+                }
+
+                // Absolute download URL for a file, each path segment encoded.
+                // Links into a protected share carry its password so they
+                // work for whoever they're sent to.
+                function directLink(item) {
+                    const path = item.path.split('/').map(encodeURIComponent).join('/')
+                    return withPassword(`${window.location.origin}/download/${path}`, item.path)
+                }
+
+                async function copyLink(item) {
+                    const ok = await copyText(directLink(item))
+                    const protectedShare = Boolean(sharePasswords[shareName(item.path)])
+                    if (!ok) showToast('Could not copy link')
+                    else showToast(protectedShare ? 'Link copied, it includes the share password' : 'Link copied', protectedShare ? 4000 : 2000)
+                }
+
+                // navigator.clipboard only exists in secure contexts, plain LAN http needs the fallback
+                async function copyText(text) {
+                    if (navigator.clipboard) {
+                        try {
+                            await navigator.clipboard.writeText(text)
+                            return true
+                        } catch (e) {
+                            console.error(e)
+                        }
+                    }
+                    const el = document.createElement('textarea')
+                    el.value = text
+                    el.style.position = 'fixed'
+                    el.style.opacity = '0'
+                    document.body.appendChild(el)
+                    el.select()
+                    const ok = document.execCommand('copy')
+                    document.body.removeChild(el)
+                    return ok
+                }
+
                 function formatSize(bytes) {
                     if (bytes === null || bytes === undefined) return '-'
                     if (bytes === 0) return '0 B'
@@ -706,30 +5383,910 @@ async fn root_handler() -> Html<&'static str> {
                     return parseFloat((bytes / Math.pow(k, i)).toFixed(1)) + ' ' + sizes[i]
                 }
 
+                function formatDate(secs) {
+                    if (!secs) return '-'
+                    return new Date(secs * 1000).toLocaleString(undefined, { dateStyle: 'medium', timeStyle: 'short' })
+                }
+
                 function getExt(name) {
                     const parts = name.split('.')
                     if (parts.length > 1) return parts.pop().slice(0, 4)
                     return 'FILE'
                 }
 
+                const kindIcons = {
+                    image: 'file-image',
+                    video: 'file-video',
+                    audio: 'file-audio',
+                    archive: 'file-archive',
+                    document: 'file-text',
+                    code: 'file-code'
+                }
+
+                function kindIcon(kind) {
+                    return kindIcons[kind] || 'file'
+                }
+
                 onMounted(() => {
-                    fetchItems('/')
+                    // Open the configured folder unless the link already points somewhere
+                    if (config.default_path && hashToPath() === '/') {
+                        history.replaceState(null, '', pathToHash(config.default_path))
+                    }
+                    fetchItems(hashToPath())
                     lucide.createIcons()
+                    window.addEventListener('keydown', onKeydown)
+                    window.addEventListener('hashchange', () => fetchItems(hashToPath()))
                     setInterval(checkConnection, 2000)
                     window.addEventListener('online', () => isConnected.value = true)
                     window.addEventListener('offline', () => isConnected.value = false)
                 })
 
                 return {
-                    items, currentPath, loading, viewMode, selectedItems, isConnected,
-                    breadcrumbs, getExt,
-                    navigate, handleOpen, downloadItem, toggleSelect, isSelected,
-                    clearSelection, downloadSelection, formatSize
+                    config, items, currentPath, loading, viewMode, selectedItems, isConnected, errorMessage,
+                    breadcrumbs, getExt, kindIcon,
+                    zipProgress, toast, speedTesting, testSpeed, copyLink, navigate, handleOpen, canDownload, downloadItem, toggleSelect, isSelected, focusIndex, onItemClick,
+                    clearSelection, downloadSelection, formatSize, formatDate,
+                    sortKey, sortOrder, setSort, toggleSort,
+                    showClipboard, clipText, clipError, snippets, toggleClipboard, shareClip, copyText
                 }
             }
         }).mount('#app')
     </script>
 </body>
 </html>
-    "##)
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    static NEXT_TREE: AtomicU64 = AtomicU64::new(0);
+
+    // Scratch folder for a test, removed again when it's dropped
+    struct TempTree(PathBuf);
+
+    impl TempTree {
+        fn new() -> Self {
+            let id = NEXT_TREE.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!("hfs-test-{}-{}", std::process::id(), id));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn file(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+
+        // The tree shared as a whole, listed at the root under `name()`
+        fn share(&self) -> SharedFolder {
+            SharedFolder::from(self.0.to_string_lossy().into_owned())
+        }
+
+        fn name(&self) -> String {
+            self.0.file_name().unwrap().to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TempTree {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn state(shares: Vec<SharedFolder>, options: serde_json::Value) -> AppState {
+        AppState::new(shares, serde_json::from_value(options).unwrap())
+    }
+
+    async fn send(state: &AppState, mut request: Request<Body>) -> Response {
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        router(state).unwrap().oneshot(request).await.unwrap()
+    }
+
+    async fn post_json(state: &AppState, uri: &str, json: serde_json::Value) -> Response {
+        let request = Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json.to_string()))
+            .unwrap();
+        send(state, request).await
+    }
+
+    async fn get(state: &AppState, uri: &str) -> Response {
+        send(state, Request::get(uri).body(Body::empty()).unwrap()).await
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()
+    }
+
+    // Names and contents of a zip's entries, in archive order
+    async fn unzip(bytes: Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        let reader = async_zip::base::read::mem::ZipFileReader::new(bytes).await.unwrap();
+        let mut entries = Vec::new();
+        for index in 0..reader.file().entries().len() {
+            let name = reader.file().entries()[index].filename().as_str().unwrap().to_string();
+            let mut data = Vec::new();
+            reader.reader_with_entry(index).await.unwrap().read_to_end_checked(&mut data).await.unwrap();
+            entries.push((name, data));
+        }
+        entries
+    }
+
+    fn names(entries: &[(String, Vec<u8>)]) -> Vec<&str> {
+        entries.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn limit_kbps_slows_a_download_down() {
+        let tree = TempTree::new();
+        tree.file("big.bin", vec![7u8; 64 * 1024]);
+        let state = state(vec![tree.share()], serde_json::json!({}));
+        let uri = format!("/download/{}/big.bin", tree.name());
+
+        let started = Instant::now();
+        assert_eq!(body(get(&state, &uri).await).await.len(), 64 * 1024);
+        let unthrottled = started.elapsed();
+
+        let started = Instant::now();
+        assert_eq!(body(get(&state, &format!("{}?limit_kbps=64", uri)).await).await.len(), 64 * 1024);
+        let throttled = started.elapsed();
+
+        assert!(throttled >= Duration::from_millis(750), "took {:?}", throttled);
+        assert!(unthrottled * 4 < throttled, "{:?} vs {:?}", unthrottled, throttled);
+    }
+
+    // Root reads past file permissions, a dangling link fails to open for anyone
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unreadable_file_is_listed_in_the_errors_manifest() {
+        let tree = TempTree::new();
+        tree.file("ok.txt", "fine");
+        std::os::unix::fs::symlink(tree.0.join("missing.txt"), tree.0.join("broken.txt")).unwrap();
+        let state = state(vec![tree.share()], serde_json::json!({}));
+
+        let entries = unzip(body(get(&state, &format!("/zip/folder/{}", tree.name())).await).await).await;
+        let ok = format!("{}/ok.txt", tree.name());
+        assert_eq!(names(&entries), [ok.as_str(), ZIP_ERRORS_ENTRY]);
+        let manifest = String::from_utf8(entries[1].1.clone()).unwrap();
+        assert!(manifest.contains(&format!("{}/broken.txt: ", tree.name())), "{}", manifest);
+    }
+
+    #[tokio::test]
+    async fn flatten_drops_the_enclosing_folder() {
+        let tree = TempTree::new();
+        tree.file("docs/a.txt", "a");
+        tree.file("docs/sub/b.txt", "b");
+        tree.file("top.txt", "top");
+        let state = state(vec![tree.share()], serde_json::json!({}));
+        let share = tree.name();
+
+        let folder = |flatten: bool| {
+            let state = state.clone();
+            let uri = format!("/zip/folder/{}/docs?flatten={}", share, flatten);
+            async move { unzip(body(get(&state, &uri).await).await).await }
+        };
+        assert_eq!(names(&folder(false).await), ["docs/a.txt", "docs/sub/b.txt"]);
+        assert_eq!(names(&folder(true).await), ["a.txt", "sub/b.txt"]);
+
+        let selection = |flatten: bool| {
+            let state = state.clone();
+            let files = [format!("{}/docs", share), format!("{}/top.txt", share)];
+            async move {
+                let response = post_json(&state, "/zip/selection", serde_json::json!({ "files": files, "flatten": flatten })).await;
+                unzip(body(response).await).await
+            }
+        };
+        let wrapped = ["docs/a.txt", "docs/sub/b.txt", "top.txt"].map(|name| format!("{}/{}", share, name));
+        assert_eq!(names(&selection(false).await), wrapped);
+        assert_eq!(names(&selection(true).await), ["a.txt", "sub/b.txt", "top.txt"]);
+    }
+
+    async fn listing(state: &AppState, uri: &str) -> Vec<serde_json::Value> {
+        serde_json::from_slice(&body(get(state, uri).await).await).unwrap()
+    }
+
+    fn listed_names(entries: &[serde_json::Value]) -> Vec<&str> {
+        entries.iter().map(|entry| entry["name"].as_str().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn image_filter_keeps_images_and_folders() {
+        let tree = TempTree::new();
+        for name in ["photo.JPG", "logo.png", "notes.txt", "song.mp3", "clip.mp4", "album/cover.jpg"] {
+            tree.file(name, "x");
+        }
+        let state = state(vec![tree.share()], serde_json::json!({}));
+
+        let entries = listing(&state, &format!("/api/browse?path={}&filter=image", tree.name())).await;
+        let mut names = listed_names(&entries);
+        names.sort();
+        assert_eq!(names, ["album", "logo.png", "photo.JPG"]);
+    }
+
+    #[tokio::test]
+    async fn non_ascii_names_get_an_encoded_filename() {
+        let expected = "attachment; filename=\"my r_sum_ _.pdf\"; filename*=UTF-8''my%20r%C3%A9sum%C3%A9%20%F0%9F%93%84.pdf";
+        assert_eq!(attachment("my résumé 📄.pdf"), expected);
+
+        let tree = TempTree::new();
+        tree.file("my résumé 📄.pdf", "%PDF");
+        let state = state(vec![tree.share()], serde_json::json!({}));
+        let uri = format!("/download/{}/my%20r%C3%A9sum%C3%A9%20%F0%9F%93%84.pdf", tree.name());
+        let response = get(&state, &uri).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_DISPOSITION], expected);
+    }
+
+    #[tokio::test]
+    async fn sites_live_under_their_prefix_next_to_ui_dir() {
+        let ui = TempTree::new();
+        ui.file("index.html", "<p>custom client</p>");
+        ui.file("app.js", "console.log('ui')");
+        let tree = TempTree::new();
+        tree.file("blog/index.html", "<h1>blog</h1>");
+        tree.file("empty/notes.txt", "no index here");
+        let state = state(
+            vec![tree.share()],
+            serde_json::json!({ "serve_index": true, "ui_dir": ui.0.to_string_lossy() }),
+        );
+
+        assert_eq!(body(get(&state, "/app.js").await).await, b"console.log('ui')");
+        let site = get(&state, &format!("/site/{}/blog/", tree.name())).await;
+        assert_eq!(site.status(), StatusCode::OK);
+        assert_eq!(body(site).await, b"<h1>blog</h1>");
+
+        let missing = get(&state, &format!("/site/{}/empty/", tree.name())).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        let text = String::from_utf8(body(missing).await).unwrap();
+        assert!(!text.contains("os error"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn manifest_stops_where_the_depth_limit_stops_the_zip() {
+        let tree = TempTree::new();
+        tree.file("a.txt", "top");
+        tree.file("one/b.txt", "one deep");
+        tree.file("one/two/c.txt", "too deep");
+        let state = state(vec![tree.share()], serde_json::json!({ "zip_max_depth": 1 }));
+
+        let zip = body(get(&state, &format!("/zip/folder/{}?stored=true", tree.name())).await).await;
+        let manifest: Vec<serde_json::Value> =
+            serde_json::from_slice(&body(get(&state, &format!("/zip/manifest/{}", tree.name())).await).await).unwrap();
+        let entries = unzip(zip.clone()).await;
+        assert_eq!(names(&entries).last(), Some(&ZIP_ERRORS_ENTRY));
+        assert_eq!(manifest.len(), entries.len() - 1);
+        for (listed, (name, data)) in manifest.iter().zip(&entries) {
+            assert_eq!(listed["name"], name.as_str());
+            let offset = listed["offset"].as_u64().unwrap() as usize;
+            assert_eq!(&zip[offset..offset + data.len()], data.as_slice());
+        }
+    }
+
+    #[tokio::test]
+    async fn aliased_share_is_browsed_downloaded_and_zipped_by_its_alias() {
+        let tree = TempTree::new();
+        tree.file("projectX-final-v3/readme.txt", "hello");
+        let share: SharedFolder = serde_json::from_value(serde_json::json!({
+            "path": tree.0.join("projectX-final-v3"),
+            "alias": "Project",
+        }))
+        .unwrap();
+        let state = state(vec![share], serde_json::json!({}));
+
+        let root = listing(&state, "/api/browse").await;
+        assert_eq!(listed_names(&root), ["Project"]);
+        assert_eq!(root[0]["path"], "Project");
+        assert_eq!(listed_names(&listing(&state, "/api/browse?path=Project").await), ["readme.txt"]);
+
+        assert_eq!(body(get(&state, "/download/Project/readme.txt").await).await, b"hello");
+        let missing = get(&state, "/download/projectX-final-v3/readme.txt").await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+
+        let entries = unzip(body(get(&state, "/zip/folder/Project").await).await).await;
+        assert_eq!(names(&entries), ["Project/readme.txt"]);
+    }
+
+    #[tokio::test]
+    async fn vanished_share_is_flagged_and_answers_503() {
+        let tree = TempTree::new();
+        let music = tree.0.join("music");
+        tree.file("music/song.mp3", "la");
+        tree.file("docs/a.txt", "a");
+        let shares = vec![
+            SharedFolder::from(music.to_string_lossy().into_owned()),
+            SharedFolder::from(tree.0.join("docs").to_string_lossy().into_owned()),
+        ];
+        let state = state(shares, serde_json::json!({}));
+        std::fs::remove_dir_all(&music).unwrap();
+
+        let root = listing(&state, "/api/browse").await;
+        let available: Vec<_> = root.iter().map(|entry| (entry["name"].as_str().unwrap(), entry["available"].as_bool())).collect();
+        assert_eq!(available, [("docs", Some(true)), ("music", Some(false))]);
+
+        let response = get(&state, "/download/music/song.mp3").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(get(&state, "/download/docs/a.txt").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn deep_tree_stops_the_zip_at_the_depth_cap() {
+        let tree = TempTree::new();
+        tree.file("top.txt", "top");
+        tree.file("1/2/ok.txt", "within the cap");
+        tree.file("1/2/3/4/5/deep.txt", "past the cap");
+        let state = state(vec![tree.share()], serde_json::json!({ "zip_max_depth": 2 }));
+        let share = tree.name();
+
+        let folder = unzip(body(get(&state, &format!("/zip/folder/{}", share)).await).await).await;
+        let selection = post_json(&state, "/zip/selection", serde_json::json!({ "files": [share] })).await;
+        let selection = unzip(body(selection).await).await;
+        for entries in [folder, selection] {
+            let expected = ["top.txt", "1/2/ok.txt"].map(|name| format!("{}/{}", share, name));
+            assert_eq!(names(&entries[..2]), expected);
+            assert_eq!(names(&entries[2..]), [ZIP_ERRORS_ENTRY]);
+            let manifest = String::from_utf8(entries[2].1.clone()).unwrap();
+            assert!(manifest.contains("is nested more than 2 folders deep"), "{}", manifest);
+        }
+    }
+
+    #[tokio::test]
+    async fn seek_into_the_middle_of_a_video() {
+        let tree = TempTree::new();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        tree.file("clip.mp4", &data);
+        let state = state(vec![tree.share()], serde_json::json!({}));
+
+        let request = Request::get(format!("/download/{}/clip.mp4?inline=true", tree.name()))
+            .header(header::RANGE, "bytes=40000-40999")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers[header::CONTENT_TYPE], "video/mp4");
+        assert_eq!(headers[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(headers[header::CONTENT_RANGE], "bytes 40000-40999/100000");
+        assert!(headers[header::CONTENT_DISPOSITION].to_str().unwrap().starts_with("inline;"));
+        assert_eq!(body(response).await, &data[40000..41000]);
+    }
+
+    #[tokio::test]
+    async fn limited_files_only_leave_through_counted_downloads() {
+        let tree = TempTree::new();
+        tree.file("public.txt", "anyone");
+        let secret = tree.file("secret.txt", "once");
+        let state = state(vec![tree.share()], serde_json::json!({ "serve_index": true }));
+        state.set_download_limit(&secret, Some(1)).unwrap();
+        let share = tree.name();
+
+        let folder = unzip(body(get(&state, &format!("/zip/folder/{}", share)).await).await).await;
+        assert_eq!(names(&folder), [format!("{}/public.txt", share)]);
+        let selection = post_json(&state, "/zip/selection", serde_json::json!({ "files": [format!("{}/secret.txt", share)] })).await;
+        let selection = unzip(body(selection).await).await;
+        assert_eq!(names(&selection), [ZIP_ERRORS_ENTRY]);
+
+        let head = get(&state, &format!("/api/head/{}/secret.txt", share)).await;
+        assert_eq!(head.status(), StatusCode::FORBIDDEN);
+
+        let page = format!("/site/{}/secret.txt", share);
+        assert_eq!(body(get(&state, &page).await).await, b"once");
+        assert_eq!(get(&state, &page).await.status(), StatusCode::GONE);
+        let download = get(&state, &format!("/download/{}/secret.txt", share)).await;
+        assert_eq!(download.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn glob_share_serves_only_matching_files() {
+        let tree = TempTree::new();
+        tree.file("Docs/a.pdf", "%PDF a");
+        tree.file("Docs/b.pdf", "%PDF b");
+        tree.file("Docs/notes.docx", "private");
+        let pattern = tree.0.join("Docs").join("*.pdf");
+        let state = state(vec![SharedFolder::from(pattern.to_string_lossy().into_owned())], serde_json::json!({}));
+
+        let mut root = listing(&state, "/api/browse").await;
+        root.sort_by_key(|entry| entry["name"].as_str().unwrap().to_string());
+        assert_eq!(listed_names(&root), ["a.pdf", "b.pdf"]);
+        assert_eq!(body(get(&state, "/download/a.pdf").await).await, b"%PDF a");
+        for uri in ["/download/notes.docx", "/download/Docs/notes.docx"] {
+            assert_eq!(get(&state, uri).await.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+        assert_ne!(get(&state, "/download/a.pdf/../notes.docx").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn excluded_git_folder_stays_out_of_zips() {
+        let tree = TempTree::new();
+        tree.file("src/main.rs", "fn main() {}");
+        tree.file(".git/HEAD", "ref: refs/heads/main");
+        tree.file(".git/objects/ab/cdef", "blob");
+        tree.file("node_modules/pkg/index.js", "module");
+        let state = state(vec![tree.share()], serde_json::json!({ "hidden_patterns": [] }));
+        let share = tree.name();
+
+        let folder = get(&state, &format!("/zip/folder/{}?exclude=.git,node_modules", share)).await;
+        let folder = unzip(body(folder).await).await;
+        let selection = post_json(&state, "/zip/selection", serde_json::json!({ "files": [share], "exclude": [".git"] })).await;
+        let selection = unzip(body(selection).await).await;
+
+        assert_eq!(names(&folder), [format!("{}/src/main.rs", share)]);
+        let selected = names(&selection);
+        assert!(selected.iter().all(|name| !name.contains(".git")), "{:?}", selected);
+        assert!(selected.contains(&format!("{}/node_modules/pkg/index.js", share).as_str()));
+    }
+
+    #[tokio::test]
+    async fn zip_list_matches_what_the_zip_holds() {
+        let tree = TempTree::new();
+        tree.file("a.txt", "a");
+        tree.file("blocked.exe", "MZ");
+        tree.file("one/b.txt", "b");
+        tree.file("one/two/three/c.txt", "too deep");
+        let options = serde_json::json!({ "max_walk_depth": 2, "denied_extensions": ["exe"] });
+        let state = state(vec![tree.share()], options);
+        let share = tree.name();
+
+        let zip = unzip(body(get(&state, &format!("/zip/folder/{}", share)).await).await).await;
+        let list = listing(&state, &format!("/zip/list/{}", share)).await;
+        let listed: Vec<_> = list.iter().map(|entry| entry["name"].as_str().unwrap()).collect();
+        assert_eq!(names(&zip), [listed.as_slice(), &[ZIP_ERRORS_ENTRY]].concat());
+        assert_eq!(listed, ["a.txt", "one/b.txt"].map(|name| format!("{}/{}", share, name)));
+        assert_eq!(list[1]["path"], format!("{}/one/b.txt", share));
+    }
+
+    #[tokio::test]
+    async fn mixed_case_and_double_extensions_are_classified() {
+        let tree = TempTree::new();
+        for name in ["Photo.JPG", "backup.TAR.GZ", "log.gz", ".bashrc"] {
+            tree.file(name, "x");
+        }
+        let state = state(vec![tree.share()], serde_json::json!({ "hidden_patterns": [] }));
+        let share = tree.name();
+
+        let photo = get(&state, &format!("/download/{}/Photo.JPG", share)).await;
+        assert_eq!(photo.headers()[header::CONTENT_TYPE], "image/jpeg");
+        let entries = listing(&state, &format!("/api/browse?path={}", share)).await;
+        let kind = |name: &str| {
+            let entry = entries.iter().find(|entry| entry["name"] == name).unwrap();
+            entry["kind"].as_str().unwrap().to_string()
+        };
+        assert_eq!(kind("Photo.JPG"), "image");
+        assert_eq!(kind("backup.TAR.GZ"), "archive");
+        assert_eq!(kind(".bashrc"), "other");
+
+        let filtered = |filter: &str| {
+            let state = state.clone();
+            let uri = format!("/api/browse?path={}&filter={}", share, filter);
+            async move {
+                let mut names: Vec<String> = listed_names(&listing(&state, &uri).await).into_iter().map(String::from).collect();
+                names.sort();
+                names
+            }
+        };
+        assert_eq!(filtered("tar.gz").await, ["backup.TAR.GZ"]);
+        assert_eq!(filtered(".GZ").await, ["backup.TAR.GZ", "log.gz"]);
+        assert!(filtered("bashrc").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn filename_override_renames_the_same_bytes() {
+        let tree = TempTree::new();
+        tree.file("doc_final_v2_REAL.pdf", "%PDF the real one");
+        let state = state(vec![tree.share()], serde_json::json!({}));
+        let uri = format!("/download/{}/doc_final_v2_REAL.pdf", tree.name());
+
+        let response = get(&state, &format!("{}?filename=Report.pdf", uri)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_DISPOSITION], attachment("Report.pdf"));
+        assert_eq!(body(response).await, b"%PDF the real one");
+
+        for name in ["..%2Fescape.pdf", "a%2Fb.pdf", "a%5Cb.pdf", "..", "%20"] {
+            let response = get(&state, &format!("{}?filename={}", uri, name)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", name);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn fifo_in_a_share_never_blocks() {
+        let tree = TempTree::new();
+        tree.file("plain.txt", "plain");
+        let fifo = tree.0.join("pipe");
+        let made = std::process::Command::new("mkfifo").arg(&fifo).status().unwrap();
+        assert!(made.success());
+        let state = state(vec![tree.share()], serde_json::json!({}));
+        let share = tree.name();
+
+        let checks = async {
+            let entries = listing(&state, &format!("/api/browse?path={}", share)).await;
+            assert_eq!(listed_names(&entries), ["plain.txt"]);
+            let download = get(&state, &format!("/download/{}/pipe", share)).await;
+            assert_eq!(download.status(), StatusCode::FORBIDDEN);
+            let zip = unzip(body(get(&state, &format!("/zip/folder/{}", share)).await).await).await;
+            assert_eq!(names(&zip), [format!("{}/plain.txt", share)]);
+        };
+        tokio::time::timeout(Duration::from_secs(5), checks).await.expect("a request blocked on the FIFO");
+    }
+
+    async fn next_frame(body: &mut Body) -> Option<Result<http_body::Frame<axum::body::Bytes>, axum::Error>> {
+        use http_body::Body as _;
+        std::future::poll_fn(|cx| Pin::new(&mut *body).poll_frame(cx)).await
+    }
+
+    #[tokio::test]
+    async fn stalled_reader_is_dropped_after_the_timeout() {
+        let tree = TempTree::new();
+        tree.file("big.bin", vec![1u8; 4 * 1024 * 1024]);
+        let state = state(vec![tree.share()], serde_json::json!({ "stall_timeout_secs": 1 }));
+
+        let mut download = get(&state, &format!("/download/{}/big.bin", tree.name())).await.into_body();
+        assert!(next_frame(&mut download).await.unwrap().is_ok());
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        let after_stall = next_frame(&mut download).await.unwrap();
+        assert_eq!(after_stall.unwrap_err().to_string(), "Download stalled");
+    }
+
+    #[tokio::test]
+    async fn extensionless_png_is_sniffed_as_an_image() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.resize(256, 0);
+        let tree = TempTree::new();
+        tree.file("screenshot", png.clone());
+        tree.file("notes", "just some text");
+        let state = state(vec![tree.share()], serde_json::json!({}));
+        let uri = format!("/download/{}/screenshot", tree.name());
+
+        let response = get(&state, &uri).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(body(response).await, png);
+        let notes = get(&state, &format!("/download/{}/notes", tree.name())).await;
+        assert_eq!(notes.headers()[header::CONTENT_TYPE], "application/octet-stream");
+
+        let ranged = Request::get(&uri).header(header::RANGE, "bytes=8-15").body(Body::empty()).unwrap();
+        let ranged = send(&state, ranged).await;
+        assert_eq!(ranged.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(ranged.headers()[header::CONTENT_TYPE], "application/octet-stream");
+    }
+
+    // Response to a GET over a real connection, the body arrives chunked
+    async fn raw_get(port: u16, path: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn restart_onto_a_taken_port_keeps_the_original_running() {
+        let tree = TempTree::new();
+        tree.file("old.txt", "old");
+        let listener = bind(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (old_tx, old_rx) = broadcast::channel(1);
+        let old = tokio::spawn(start_server(listener.try_clone().unwrap(), state(vec![tree.share()], serde_json::json!({})), old_rx));
+
+        // Someone else holds the new port: binding fails before the old server is touched
+        let taken = std::net::TcpListener::bind(SocketAddr::from((BIND_ADDRESS, 0))).unwrap();
+        assert!(bind(taken.local_addr().unwrap().port()).is_err());
+        let uri = format!("/download/{}/old.txt", tree.name());
+        assert!(raw_get(port, &uri).await.contains("\r\nold\r\n"));
+
+        // Restarting on its own port takes over the socket, then stops the old server
+        let next = TempTree::new();
+        next.file("new.txt", "new");
+        let (new_tx, new_rx) = broadcast::channel(1);
+        let new = tokio::spawn(start_server(listener, state(vec![next.share()], serde_json::json!({})), new_rx));
+        old_tx.send(()).unwrap();
+        old.await.unwrap().unwrap();
+        let uri = format!("/download/{}/new.txt", next.name());
+        assert!(raw_get(port, &uri).await.contains("\r\nnew\r\n"));
+
+        new_tx.send(()).unwrap();
+        new.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn feed_links_follow_the_request_scheme_and_password() {
+        let tree = TempTree::new();
+        tree.file("new report.pdf", "%PDF");
+        tree.file("setup.exe", "MZ");
+        let once = tree.file("once.txt", "one download");
+        let share: SharedFolder = serde_json::from_value(serde_json::json!({ "path": tree.0, "password": "s3cret&more" })).unwrap();
+        let state = state(vec![share], serde_json::json!({ "denied_extensions": ["exe"] }));
+        state.set_download_limit(&once, Some(1)).unwrap();
+
+        let request = Request::get(format!("/api/feed/{}?share_password=s3cret%26more", tree.name()))
+            .header(header::HOST, "files.example.org")
+            .header("x-forwarded-proto", "https")
+            .body(Body::empty())
+            .unwrap();
+        let feed = String::from_utf8(body(send(&state, request).await).await).unwrap();
+        let link = format!("https://files.example.org/download/{}/new%20report.pdf?share_password=s3cret%26more", tree.name());
+        assert!(feed.contains(&format!("<link>{}</link>", escape_html(&link))), "{}", feed);
+        assert_eq!(feed.matches("<item>").count(), 1, "{}", feed);
+
+        let link = get(&state, &format!("/download/{}/new%20report.pdf?share_password=s3cret%26more", tree.name())).await;
+        assert_eq!(link.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn very_deep_tree_is_capped_by_max_walk_depth() {
+        let tree = TempTree::new();
+        let mut dir = String::new();
+        for level in 0..80 {
+            tree.file(&format!("{}f{}.txt", dir, level), "x");
+            dir.push_str("d/");
+        }
+        let share = tree.name();
+
+        // The default cap is finite even without the option
+        let defaults = state(vec![tree.share()], serde_json::json!({}));
+        let roots = listing(&defaults, "/api/browse?totals=1").await;
+        assert_eq!(roots[0]["file_count"], DEFAULT_MAX_WALK_DEPTH + 1);
+        assert_eq!(roots[0]["totals_truncated"], true);
+
+        let state = state(vec![tree.share()], serde_json::json!({ "max_walk_depth": 3 }));
+        let roots = listing(&state, "/api/browse?totals=1").await;
+        assert_eq!(roots[0]["file_count"], 4);
+        let text = String::from_utf8(body(get(&state, &format!("/api/tree/{}?depth=50", share)).await).await).unwrap();
+        assert!(text.contains("f2.txt") && !text.contains("f3.txt"), "{}", text);
+        let zip = unzip(body(get(&state, &format!("/zip/folder/{}", share)).await).await).await;
+        assert_eq!(names(&zip).last(), Some(&ZIP_ERRORS_ENTRY));
+        assert_eq!(zip.len(), 4 + 1);
+        let started = post_json(&state, "/zip/folder/start", serde_json::json!({ "path": share })).await;
+        let started: serde_json::Value = serde_json::from_slice(&body(started).await).unwrap();
+        assert_eq!(started["total"], 4);
+        assert_eq!(started["truncated"], true);
+    }
+
+    #[test]
+    fn unique_name_suffixes_before_the_extension() {
+        let mut taken = HashSet::new();
+        let added = [
+            ("a.txt", false),
+            ("a.txt", false),
+            ("a.txt", false),
+            ("docs.v1", true),
+            ("docs.v1", true),
+            (".env", false),
+            (".env", false),
+        ];
+        let names: Vec<_> = added
+            .into_iter()
+            .map(|(name, is_dir)| unique_name(name, is_dir, &mut taken))
+            .collect();
+        assert_eq!(names, ["a.txt", "a (2).txt", "a (3).txt", "docs.v1", "docs.v1 (2)", ".env", ".env (2)"]);
+    }
+
+    #[tokio::test]
+    async fn merged_roots_rename_colliding_names() {
+        let first = TempTree::new();
+        first.file("readme.txt", "first");
+        first.file("docs/a.txt", "a");
+        let second = TempTree::new();
+        second.file("readme.txt", "second");
+        second.file("docs/b.txt", "b");
+        let state = state(vec![first.share(), second.share()], serde_json::json!({ "merge_roots": true }));
+
+        let roots = listing(&state, "/api/browse").await;
+        assert_eq!(listed_names(&roots), ["docs", "docs (2)", "readme (2).txt", "readme.txt"]);
+        assert_eq!(body(get(&state, "/download/readme.txt").await).await, b"first");
+        assert_eq!(body(get(&state, "/download/readme%20(2).txt").await).await, b"second");
+        assert_eq!(listed_names(&listing(&state, "/api/browse?path=docs%20(2)").await), ["b.txt"]);
+    }
+
+    #[tokio::test]
+    async fn merged_roots_keep_a_protected_share_locked() {
+        let open = TempTree::new();
+        open.file("readme.txt", "open");
+        let locked = TempTree::new();
+        locked.file("salaries.xlsx", "secret");
+        let share: SharedFolder = serde_json::from_value(serde_json::json!({ "path": locked.0, "password": "pw" })).unwrap();
+        let state = state(vec![open.share(), share], serde_json::json!({ "merge_roots": true }));
+
+        let roots = listing(&state, "/api/browse?totals=1").await;
+        let mut names = listed_names(&roots);
+        names.sort();
+        assert_eq!(names, [locked.name().as_str(), "readme.txt"]);
+        let entry = roots.iter().find(|entry| entry["name"] == locked.name()).unwrap();
+        assert_eq!(entry["protected"], true);
+        assert!(entry.get("file_count").is_none());
+
+        let uri = format!("/api/browse?path={}", locked.name());
+        assert_eq!(get(&state, &uri).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(listed_names(&listing(&state, &format!("{}&share_password=pw", uri)).await), ["salaries.xlsx"]);
+        assert_eq!(get(&state, "/download/salaries.xlsx").await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn denied_exe_is_refused_even_by_direct_url() {
+        let tree = TempTree::new();
+        for name in ["setup.exe", "SETUP2.EXE", "guide.pdf", "README"] {
+            tree.file(name, "x");
+        }
+        let share = tree.name();
+        let status = |state: &AppState, name: &str| {
+            let state = state.clone();
+            let uri = format!("/download/{}/{}", share, name);
+            async move { get(&state, &uri).await.status() }
+        };
+
+        let denied = state(vec![tree.share()], serde_json::json!({ "denied_extensions": ["exe"] }));
+        assert_eq!(status(&denied, "setup.exe").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&denied, "SETUP2.EXE").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&denied, "guide.pdf").await, StatusCode::OK);
+        assert_eq!(status(&denied, "README").await, StatusCode::OK);
+        // Still listed unless hide_blocked_files asks otherwise
+        assert_eq!(listing(&denied, &format!("/api/browse?path={}", share)).await.len(), 4);
+
+        let options = serde_json::json!({ "allowed_extensions": ["pdf"], "hide_blocked_files": true });
+        let allowed = state(vec![tree.share()], options);
+        assert_eq!(status(&allowed, "guide.pdf").await, StatusCode::OK);
+        assert_eq!(status(&allowed, "setup.exe").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&allowed, "README").await, StatusCode::FORBIDDEN);
+        assert_eq!(listed_names(&listing(&allowed, &format!("/api/browse?path={}", share)).await), ["guide.pdf"]);
+    }
+
+    #[tokio::test]
+    async fn if_range_falls_back_to_the_full_file_once_it_changed() {
+        let tree = TempTree::new();
+        let path = tree.file("movie.mkv", "0123456789");
+        let state = state(vec![tree.share()], serde_json::json!({}));
+        let uri = format!("/download/{}/movie.mkv", tree.name());
+        let resume = |etag: HeaderValue| {
+            let state = state.clone();
+            let request = Request::get(&uri).header(header::RANGE, "bytes=4-").header(header::IF_RANGE, etag);
+            async move { send(&state, request.body(Body::empty()).unwrap()).await }
+        };
+
+        let etag = get(&state, &uri).await.headers()[header::ETAG].clone();
+        let unchanged = resume(etag.clone()).await;
+        assert_eq!(unchanged.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body(unchanged).await, b"456789");
+
+        std::fs::write(&path, "a whole new file").unwrap();
+        let changed = resume(etag).await;
+        assert_eq!(changed.status(), StatusCode::OK);
+        assert_eq!(body(changed).await, b"a whole new file");
+    }
+
+    #[tokio::test]
+    async fn deterministic_zip_resumes_across_an_entry_boundary() {
+        let tree = TempTree::new();
+        tree.file("a.txt", vec![b'a'; 3000]);
+        tree.file("b/c.txt", vec![b'c'; 5000]);
+        std::fs::create_dir(tree.0.join("empty")).unwrap();
+        let state = state(vec![tree.share()], serde_json::json!({}));
+        let uri = format!("/zip/folder/{}?deterministic=1", tree.name());
+
+        let full = get(&state, &uri).await;
+        let len: usize = full.headers()[header::CONTENT_LENGTH].to_str().unwrap().parse().unwrap();
+        let etag = full.headers()[header::ETAG].clone();
+        let full = body(full).await;
+        assert_eq!(full.len(), len);
+        assert_eq!(names(&unzip(full.clone()).await).len(), 2);
+
+        // a.txt's data ends a header, a name and 3000 bytes in, so both
+        // ranges start inside it and run on into the next entry
+        for (start, end) in [(2900, 3499), (2900, len - 1)] {
+            let request = Request::get(&uri)
+                .header(header::RANGE, format!("bytes={}-{}", start, end))
+                .header(header::IF_RANGE, etag.clone())
+                .body(Body::empty())
+                .unwrap();
+            let ranged = send(&state, request).await;
+            assert_eq!(ranged.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(ranged.headers()[header::CONTENT_LENGTH], (end - start + 1).to_string());
+            assert_eq!(body(ranged).await, &full[start..=end]);
+        }
+
+        let empty = get(&state, &format!("/zip/folder/{}/empty?deterministic=1", tree.name())).await;
+        assert_eq!(empty.headers()[header::CONTENT_LENGTH], "22");
+        assert_eq!(body(empty).await.len(), 22);
+    }
+
+    #[tokio::test]
+    async fn deterministic_zip_is_refused_when_the_zip_would_be_cut_short() {
+        let tree = TempTree::new();
+        tree.file("top.txt", "top");
+        tree.file("1/2/3/deep.txt", "past the cap");
+        let state = state(vec![tree.share()], serde_json::json!({ "zip_max_depth": 1 }));
+
+        let response = get(&state, &format!("/zip/folder/{}?deterministic=1", tree.name())).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn zip_progress_total_matches_what_the_zip_holds() {
+        let tree = TempTree::new();
+        tree.file("a.txt", vec![b'a'; 1000]);
+        tree.file("setup.exe", vec![b'x'; 5000]);
+        tree.file("cache/big.bin", vec![b'c'; 7000]);
+        tree.file("1/2/3/deep.txt", vec![b'd'; 3000]);
+        let options = serde_json::json!({ "denied_extensions": ["exe"], "zip_max_depth": 2 });
+        let state = state(vec![tree.share()], options);
+        let share = tree.name();
+
+        let started = post_json(&state, "/zip/folder/start", serde_json::json!({ "path": share, "exclude": ["cache"] })).await;
+        let started: serde_json::Value = serde_json::from_slice(&body(started).await).unwrap();
+        let zip = get(&state, &format!("/zip/folder/{}?exclude=cache&job={}", share, started["job"].as_str().unwrap())).await;
+        let zip = unzip(body(zip).await).await;
+        let zipped: usize = zip.iter().filter(|(name, _)| name != ZIP_ERRORS_ENTRY).map(|(_, data)| data.len()).sum();
+        assert_eq!(started["total"], 1000);
+        assert_eq!(started["truncated"], true);
+        assert_eq!(zipped, 1000);
+    }
+
+    #[tokio::test]
+    async fn cached_listing_takes_paths_from_the_request() {
+        let tree = TempTree::new();
+        tree.file("docs/a.txt", "a");
+        let alias: SharedFolder = serde_json::from_value(serde_json::json!({ "path": tree.0.join("docs"), "alias": "Docs" })).unwrap();
+        let state = state(vec![tree.share(), alias], serde_json::json!({}));
+
+        let nested = listing(&state, &format!("/api/browse?path={}/docs", tree.name())).await;
+        assert_eq!(nested[0]["path"], format!("{}/docs/a.txt", tree.name()));
+        for uri in ["/api/browse?path=Docs", "/api/browse?path=Docs/"] {
+            assert_eq!(listing(&state, uri).await[0]["path"], "Docs/a.txt", "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn protected_downloads_and_zip_jobs_need_the_share_password() {
+        let open = TempTree::new();
+        open.file("public.txt", "public");
+        let locked = TempTree::new();
+        locked.file("secret.txt", "secret");
+        let share: SharedFolder = serde_json::from_value(serde_json::json!({ "path": locked.0, "password": "pw" })).unwrap();
+        let state = state(vec![open.share(), share], serde_json::json!({}));
+        let unlock = "share_password=pw";
+
+        body(get(&state, &format!("/download/{}/public.txt", open.name())).await).await;
+        body(get(&state, &format!("/download/{}/secret.txt?{}", locked.name(), unlock)).await).await;
+        body(get(&state, &format!("/zip/folder/{}?{}", locked.name(), unlock)).await).await;
+        let recent = |uri: &'static str| {
+            let state = state.clone();
+            async move {
+                let records = listing(&state, uri).await;
+                records.iter().map(|record| record["path"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(recent("/api/downloads/recent").await, [format!("{}/public.txt", open.name())]);
+        assert_eq!(recent("/api/downloads/recent?share_password=pw").await.len(), 3);
+
+        let started = post_json(&state, &format!("/zip/folder/start?{}", unlock), serde_json::json!({ "path": locked.name() })).await;
+        let started: serde_json::Value = serde_json::from_slice(&body(started).await).unwrap();
+        let job = started["job"].as_str().unwrap();
+        assert!(job.len() == 32 && job.bytes().all(|byte| byte.is_ascii_hexdigit()), "{}", job);
+        let watched = get(&state, &format!("/zip/progress/{}", job)).await;
+        assert_eq!(watched.status(), StatusCode::UNAUTHORIZED);
+        let watched = get(&state, &format!("/zip/progress/{}?{}", job, unlock)).await;
+        assert_eq!(watched.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn connection_goes_idle_only_after_its_last_request_ends() {
+        let activity = Arc::new(ConnectionActivity::default());
+        let keep_alive = Duration::from_millis(200);
+        // Before the first request hyper's header timer is in charge
+        assert!(tokio::time::timeout(Duration::from_millis(400), activity.idle(keep_alive)).await.is_err());
+
+        let request = RequestInFlight::start(activity.clone());
+        assert!(tokio::time::timeout(Duration::from_millis(400), activity.idle(keep_alive)).await.is_err());
+        let waiting = tokio::spawn({
+            let activity = activity.clone();
+            async move { activity.idle(keep_alive).await }
+        });
+        let ended = Instant::now();
+        drop(request);
+        tokio::time::timeout(Duration::from_secs(2), waiting).await.unwrap().unwrap();
+        assert!(ended.elapsed() >= keep_alive, "{:?}", ended.elapsed());
+    }
 }
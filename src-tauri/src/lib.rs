@@ -1,12 +1,104 @@
 pub mod http;
 pub mod network;
 
-use std::sync::Mutex;
-use tauri::State;
+use qrcode::{render::svg, QrCode};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, State};
+use tauri_plugin_clipboard_manager::ClipboardExt;
+use tauri_plugin_dialog::DialogExt;
+use tauri_plugin_log::{RotationStrategy, Target, TargetKind};
 use tokio::sync::broadcast;
 
+// Logs go to <app log dir>/hfs.log, rotated once it reaches LOG_MAX_BYTES
+const LOG_FILE_NAME: &str = "hfs";
+const LOG_MAX_BYTES: u128 = 5 * 1024 * 1024;
+const LOG_KEEP_FILES: usize = 3;
+// Only the tail of the log is sent back to the UI
+const LOG_TAIL_BYTES: u64 = 256 * 1024;
+
+// Running servers by id. Ids are never reused, so a stale id from the UI
+// can't reach a server started later.
 struct ServiceState {
-    shutdown_tx: Mutex<Option<broadcast::Sender<()>>>,
+    servers: Mutex<BTreeMap<u64, RunningServer>>,
+    next_id: AtomicU64,
+    public_ip: Mutex<Option<CachedPublicIp>>,
+}
+
+// The public IP rarely changes, and asking again on every click while
+// offline would keep the UI waiting for the timeout
+const PUBLIC_IP_CACHE_TTL: Duration = Duration::from_secs(60);
+const PUBLIC_IP_TIMEOUT: Duration = Duration::from_secs(3);
+
+struct CachedPublicIp {
+    endpoint: String,
+    ip: Option<String>,
+    fetched_at: Instant,
+}
+
+struct RunningServer {
+    port: u16,
+    state: http::AppState,
+    external_ip: Option<String>,
+    // Handed to the next server started on the same port, so the port
+    // never stops accepting connections during a restart
+    listener: std::net::TcpListener,
+    // Released by the server task once it stops, unless a restart on the
+    // same port took it over first
+    mapping: Arc<Mutex<Option<network::PortMapping>>>,
+    shutdown_tx: broadcast::Sender<()>,
+    task: tauri::async_runtime::JoinHandle<()>,
+}
+
+impl ServiceState {
+    // Runs `f` on the server with `id`, or on the most recently started one
+    // when no id is given
+    fn with_server<T>(&self, id: Option<u64>, f: impl FnOnce(&RunningServer) -> T) -> Option<T> {
+        let servers = self.servers.lock().unwrap();
+        let server = match id {
+            Some(id) => servers.get(&id),
+            None => servers.values().next_back(),
+        };
+        server.map(f)
+    }
+}
+
+#[derive(Serialize)]
+struct ServerSummary {
+    id: u64,
+    port: u16,
+    external_ip: Option<String>,
+    shared_folders: Vec<http::SharedFolder>,
+}
+
+// How long stop_all_servers waits for servers to finish shutting down
+const STOP_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct StopSummary {
+    /// 0 when nothing was running
+    stopped: usize,
+    /// Some server was still sending files when it was stopped
+    had_active_transfers: bool,
+}
+
+// Everything the desktop UI needs to show the running server's settings
+#[derive(Default, Serialize)]
+struct FullConfig {
+    running: bool,
+    id: Option<u64>,
+    port: Option<u16>,
+    bind_address: Option<String>,
+    external_ip: Option<String>,
+    shared_folders: Vec<http::SharedFolder>,
+    options: Option<http::ServerOptions>,
 }
 
 #[tauri::command]
@@ -14,62 +106,698 @@ fn get_local_ips() -> Vec<String> {
     network::get_local_ips()
 }
 
+// Ok(false) when the port is taken, Err for anything else that prevents
+// binding it, such as a privileged port
 #[tauri::command]
+fn is_port_available(port: u16, bind_addr: Option<String>) -> Result<bool, String> {
+    let addr = match bind_addr {
+        Some(addr) => addr.parse().map_err(|_| format!("Invalid bind address: {}", addr))?,
+        None => IpAddr::from(http::BIND_ADDRESS),
+    };
+    network::is_port_available(addr, port).map_err(|e| match e.kind() {
+        ErrorKind::PermissionDenied => format!("Port {} requires administrator privileges", port),
+        ErrorKind::AddrNotAvailable => format!("{} is not an address of this machine", addr),
+        _ => e.to_string(),
+    })
+}
+
+#[derive(Default, Serialize)]
+struct StartInfo {
+    /// Identifies the server in the other commands
+    id: u64,
+    external_ip: Option<String>,
+    upnp_error: Option<String>,
+    skipped_folders: Vec<http::InvalidFolder>,
+    stop_after_secs: Option<u64>,
+}
+
+// Payload of the `server-stopped` event sent when a server stops on its own
+#[derive(Clone, Serialize)]
+struct ServerStopped {
+    id: u64,
+    reason: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum StartError {
+    InvalidFolders { folders: Vec<http::InvalidFolder> },
+    InvalidDefaultPath { message: String },
+    InvalidOptions { message: String },
+    BindFailed { message: String },
+    NoShares,
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
 async fn start_server_cmd(
     state: State<'_, ServiceState>,
     port: u16,
-    shared_folders: Vec<String>,
-) -> Result<(), String> {
+    shared_folders: Vec<http::SharedFolder>,
+    enable_upnp: Option<bool>,
+    skip_invalid: Option<bool>,
+    allow_empty: Option<bool>,
+    options: Option<http::ServerOptions>,
+    stop_after_secs: Option<u64>,
+    app: AppHandle,
+) -> Result<StartInfo, StartError> {
+    let mut info = StartInfo::default();
+
+    // Validate before touching the running server so a typo doesn't take it down
+    let (shared_folders, invalid) = http::validate_shared_folders(shared_folders).await;
+    if !invalid.is_empty() {
+        if !skip_invalid.unwrap_or(false) {
+            return Err(StartError::InvalidFolders { folders: invalid });
+        }
+        for folder in &invalid {
+            log::warn!("Skipping shared folder {}: {}", folder.path, folder.reason);
+        }
+        info.skipped_folders = invalid;
+    }
+    // A server with nothing to share only shows guests a blank page
+    if shared_folders.is_empty() && !allow_empty.unwrap_or(false) {
+        return Err(StartError::NoShares);
+    }
+
+    let options = options.unwrap_or_default();
+    if let Some(default_path) = &options.default_path {
+        http::validate_default_path(&shared_folders, &options, default_path)
+            .map_err(|message| StartError::InvalidDefaultPath { message })?;
+    }
+    options
+        .custom_headers()
+        .map_err(|message| StartError::InvalidOptions { message })?;
+    options
+        .error_page_template()
+        .map_err(|message| StartError::InvalidOptions { message })?;
+    options
+        .ui_dir()
+        .map_err(|message| StartError::InvalidOptions { message })?;
+
+    // Starting on a port that is already served replaces that server. The
+    // new one takes over its socket, so the old server is only stopped once
+    // the new one is sure to be listening, and keeps running if it isn't.
+    let replaced = state
+        .servers
+        .lock()
+        .unwrap()
+        .values()
+        .find(|server| server.port == port)
+        .map(|server| server.listener.try_clone());
+    let listener = match replaced {
+        Some(listener) => listener.map_err(|e| e.to_string()),
+        None => http::bind(port),
+    }
+    .map_err(|message| StartError::BindFailed { message })?;
+    let server_listener = listener.try_clone().map_err(|e| StartError::BindFailed { message: e.to_string() })?;
+
     let (tx, rx) = broadcast::channel(1);
-    
-    // Stop existing server if any
+
+    // Stopping the replaced server also cancels its auto-stop timer. Other
+    // servers keep running. Its gateway mapping is taken before it stops, so
+    // its task can't remove a mapping the new server still relies on.
+    let mut handed_over = None;
     {
-        let mut shutdown_tx = state.shutdown_tx.lock().unwrap();
-        if let Some(tx) = shutdown_tx.take() {
-            let _ = tx.send(());
-        }
-        *shutdown_tx = Some(tx);
+        let mut servers = state.servers.lock().unwrap();
+        servers.retain(|_, server| {
+            if server.port != port {
+                return true;
+            }
+            if let Some(mapping) = server.mapping.lock().unwrap().take() {
+                handed_over = Some((mapping, server.external_ip.clone()));
+            }
+            let _ = server.shutdown_tx.send(());
+            false
+        });
     }
 
+    let mapping = match (enable_upnp.unwrap_or(false), handed_over) {
+        (true, Some((mapping, external_ip))) => {
+            info.external_ip = external_ip;
+            Some(mapping)
+        }
+        (true, None) => match network::add_port_mapping(port).await {
+            Ok((mapping, ip)) => {
+                info.external_ip = Some(ip.to_string());
+                Some(mapping)
+            }
+            Err(e) => {
+                info.upnp_error = Some(e);
+                None
+            }
+        },
+        (false, Some((mapping, _))) => {
+            mapping.remove().await;
+            None
+        }
+        (false, None) => None,
+    };
+    let mapping = Arc::new(Mutex::new(mapping));
+    let server_mapping = mapping.clone();
+
+    let app_state = http::AppState::new(shared_folders, options);
+    let server_state = app_state.clone();
+
     // Spawn server task
-    tauri::async_runtime::spawn(async move {
-        if let Err(e) = http::start_server(port, shared_folders, rx).await {
+    let task = tauri::async_runtime::spawn(async move {
+        if let Err(e) = http::start_server(server_listener, server_state, rx).await {
             eprintln!("Server error: {}", e);
         }
+        // Server has shut down, release the gateway mapping
+        let mapping = server_mapping.lock().unwrap().take();
+        if let Some(mapping) = mapping {
+            mapping.remove().await;
+        }
+    });
+    let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+    info.id = id;
+    state.servers.lock().unwrap().insert(id, RunningServer {
+        port,
+        state: app_state,
+        external_ip: info.external_ip.clone(),
+        listener,
+        mapping,
+        shutdown_tx: tx.clone(),
+        task,
     });
 
+    if let Some(secs) = stop_after_secs.filter(|&secs| secs > 0) {
+        info.stop_after_secs = Some(secs);
+        let mut stopped = tx.subscribe();
+        tauri::async_runtime::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(secs)) => {
+                    let state = app.state::<ServiceState>();
+                    if stop_if_current(&state, id, &tx) {
+                        log::info!("Stopping server {} after {}s", id, secs);
+                        let _ = app.emit("server-stopped", ServerStopped { id, reason: "timeout" });
+                    }
+                }
+                // Stopped manually or replaced by a restart
+                _ = stopped.recv() => {}
+            }
+        });
+    }
+
+    Ok(info)
+}
+
+// Like stop_server_cmd, but reports what was stopped and gives the servers a
+// moment to shut down before returning
+#[tauri::command]
+async fn stop_all_servers(state: State<'_, ServiceState>) -> Result<StopSummary, String> {
+    let servers: Vec<RunningServer> = std::mem::take(&mut *state.servers.lock().unwrap()).into_values().collect();
+    let had_active_transfers = servers
+        .iter()
+        .any(|server| server.state.transfer_stats().active_transfers > 0);
+    for server in &servers {
+        let _ = server.shutdown_tx.send(());
+    }
+
+    let stopped = servers.len();
+    for server in servers {
+        if tokio::time::timeout(STOP_GRACE_PERIOD, server.task).await.is_err() {
+            log::warn!("Server on port {} is still closing connections", server.port);
+        }
+    }
+    Ok(StopSummary { stopped, had_active_transfers })
+}
+
+// Stops server `id` only if `tx` still belongs to it
+fn stop_if_current(state: &ServiceState, id: u64, tx: &broadcast::Sender<()>) -> bool {
+    let mut servers = state.servers.lock().unwrap();
+    if !servers.get(&id).is_some_and(|server| server.shutdown_tx.same_channel(tx)) {
+        return false;
+    }
+    servers.remove(&id);
+    let _ = tx.send(());
+    true
+}
+
+// Stops server `id`, or every server when no id is given
+#[tauri::command]
+async fn stop_server_cmd(state: State<'_, ServiceState>, id: Option<u64>) -> Result<(), String> {
+    let mut servers = state.servers.lock().unwrap();
+    servers.retain(|server_id, server| {
+        if id.is_some_and(|id| id != *server_id) {
+            return true;
+        }
+        let _ = server.shutdown_tx.send(());
+        false
+    });
     Ok(())
 }
 
 #[tauri::command]
-async fn stop_server_cmd(state: State<'_, ServiceState>) -> Result<(), String> {
-    let mut shutdown_tx = state.shutdown_tx.lock().unwrap();
-    if let Some(tx) = shutdown_tx.take() {
-        let _ = tx.send(());
+fn list_servers(state: State<'_, ServiceState>) -> Vec<ServerSummary> {
+    state
+        .servers
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, server)| ServerSummary {
+            id: *id,
+            port: server.port,
+            external_ip: server.external_ip.clone(),
+            shared_folders: server.state.shared_folders.lock().unwrap().clone(),
+        })
+        .collect()
+}
+
+#[tauri::command]
+fn get_recent_downloads(state: State<'_, ServiceState>, id: Option<u64>) -> Vec<http::DownloadRecord> {
+    state
+        .with_server(id, |server| server.state.recent_downloads())
+        .unwrap_or_default()
+}
+
+// Totals for a running server, all zero while it is stopped
+#[tauri::command]
+fn get_transfer_stats(state: State<'_, ServiceState>, id: Option<u64>) -> http::TransferStats {
+    state
+        .with_server(id, |server| server.state.transfer_stats())
+        .unwrap_or_default()
+}
+
+// Opens a multi-select folder picker and returns the chosen folders as
+// canonical absolute paths, without duplicates or anything that isn't a folder
+#[tauri::command]
+async fn pick_shared_folders(app: AppHandle) -> Vec<String> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    app.dialog().file().pick_folders(move |folders| {
+        let _ = tx.send(folders.unwrap_or_default());
+    });
+    let picked = rx.await.unwrap_or_default();
+
+    let mut folders: Vec<String> = Vec::new();
+    for folder in picked {
+        let Ok(path) = folder.into_path() else {
+            continue;
+        };
+        let Ok(path) = path.canonicalize() else {
+            continue;
+        };
+        if !path.is_dir() {
+            continue;
+        }
+        let path = path.to_string_lossy().into_owned();
+        if !folders.contains(&path) {
+            folders.push(path);
+        }
+    }
+    folders
+}
+
+// Limits how many more times a shared file can be downloaded, `None` lifts it.
+// Limits live in the running server and are dropped when it stops.
+#[tauri::command]
+fn set_download_limit(
+    state: State<'_, ServiceState>,
+    id: Option<u64>,
+    path: String,
+    max_downloads: Option<u32>,
+) -> Result<(), String> {
+    state
+        .with_server(id, |server| server.state.set_download_limit(Path::new(&path), max_downloads))
+        .ok_or("Server is not running")?
+}
+
+// Shows a shared item selected in Finder/Explorer. Only paths under a
+// running server's shares are accepted so this can't open arbitrary files.
+#[tauri::command]
+fn reveal_in_explorer(state: State<'_, ServiceState>, path: String) -> Result<(), String> {
+    let shared = state
+        .servers
+        .lock()
+        .unwrap()
+        .values()
+        .any(|server| server.state.is_shared(Path::new(&path)));
+    if !shared {
+        return Err(format!("{} is not shared", path));
+    }
+
+    #[cfg(target_os = "macos")]
+    let result = Command::new("open").arg("-R").arg(&path).spawn();
+    #[cfg(target_os = "windows")]
+    let result = Command::new("explorer").arg(format!("/select,{}", path)).spawn();
+    // No common way to select a file on Linux, open the containing folder instead
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    let result = {
+        let path = Path::new(&path);
+        let dir = if path.is_dir() { path } else { path.parent().unwrap_or(path) };
+        Command::new("xdg-open").arg(dir).spawn()
+    };
+
+    result.map(|_| ()).map_err(|e| e.to_string())
+}
+
+#[derive(Serialize)]
+struct ShareCode {
+    ip: String,
+    url: String,
+    svg: String,
+}
+
+fn qr_svg(text: &str) -> Result<String, String> {
+    let code = QrCode::new(text.as_bytes()).map_err(|e| e.to_string())?;
+    Ok(code
+        .render::<svg::Color>()
+        .min_dimensions(160, 160)
+        .dark_color(svg::Color("#18181b"))
+        .light_color(svg::Color("#ffffff"))
+        .build())
+}
+
+// One QR code per LAN address, guests scan whichever one their network can reach
+#[tauri::command]
+fn get_share_qr_codes(state: State<'_, ServiceState>, id: Option<u64>) -> Result<Vec<ShareCode>, String> {
+    let port = state
+        .with_server(id, |server| server.port)
+        .ok_or("Server is not running")?;
+    network::get_local_ips()
+        .into_iter()
+        .map(|ip| {
+            let url = network::share_url(&ip, port);
+            Ok(ShareCode { svg: qr_svg(&url)?, ip, url })
+        })
+        .collect()
+}
+
+// Settings of server `id`, or of the most recently started one
+#[tauri::command]
+fn get_full_config(state: State<'_, ServiceState>, id: Option<u64>) -> FullConfig {
+    let servers = state.servers.lock().unwrap();
+    let server = match id {
+        Some(id) => servers.get_key_value(&id),
+        None => servers.iter().next_back(),
+    };
+    let Some((id, server)) = server else {
+        return FullConfig::default();
+    };
+    let shared_folders = server.state.shared_folders.lock().unwrap().clone();
+    FullConfig {
+        running: true,
+        id: Some(*id),
+        port: Some(server.port),
+        bind_address: Some(std::net::Ipv4Addr::from(http::BIND_ADDRESS).to_string()),
+        external_ip: server.external_ip.clone(),
+        shared_folders,
+        options: Some(server.state.options.as_ref().clone()),
+    }
+}
+
+// Settings saved by export_config. Share passwords are only kept as hashes,
+// so they can't be exported; the shares that had one are listed instead.
+#[derive(Serialize, Deserialize)]
+struct ExportedConfig {
+    port: u16,
+    shared_folders: Vec<http::SharedFolder>,
+    #[serde(default)]
+    options: http::ServerOptions,
+    /// Paths of the shares whose password has to be given again on import
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    password_required: Vec<String>,
+}
+
+// Server `id`'s settings as JSON, for import_config on this or another machine
+#[tauri::command]
+fn export_config(state: State<'_, ServiceState>, id: Option<u64>) -> Result<String, String> {
+    let config = state
+        .with_server(id, |server| {
+            let shared_folders = server.state.shared_folders.lock().unwrap().clone();
+            ExportedConfig {
+                port: server.port,
+                password_required: shared_folders
+                    .iter()
+                    .filter(|folder| folder.is_protected())
+                    .map(|folder| folder.path.clone())
+                    .collect(),
+                shared_folders,
+                options: server.state.options.as_ref().clone(),
+            }
+        })
+        .ok_or("Server is not running")?;
+    serde_json::to_string_pretty(&config).map_err(|e| e.to_string())
+}
+
+// Starts a server from export_config's JSON. `passwords` maps the paths in
+// `password_required` to their passwords; a share missing one is refused
+// rather than started unprotected.
+#[tauri::command]
+async fn import_config(
+    state: State<'_, ServiceState>,
+    json: String,
+    passwords: Option<BTreeMap<String, String>>,
+    app: AppHandle,
+) -> Result<StartInfo, StartError> {
+    let invalid = |message: String| StartError::InvalidOptions { message };
+    let mut config: ExportedConfig =
+        serde_json::from_str(&json).map_err(|e| invalid(format!("Invalid config file: {}", e)))?;
+    let passwords = passwords.unwrap_or_default();
+    for folder in &mut config.shared_folders {
+        if !config.password_required.contains(&folder.path) {
+            continue;
+        }
+        match passwords.get(&folder.path).filter(|password| !password.is_empty()) {
+            Some(password) => folder.set_password(password),
+            None => return Err(invalid(format!("{} needs its password to be entered again", folder.path))),
+        }
+    }
+    start_server_cmd(state, config.port, config.shared_folders, None, None, None, Some(config.options), None, app).await
+}
+
+// Copies the running server's URL for `ip` and returns it for the UI to confirm
+#[tauri::command]
+fn copy_share_url(
+    app: AppHandle,
+    state: State<'_, ServiceState>,
+    id: Option<u64>,
+    ip: String,
+) -> Result<String, String> {
+    let port = state
+        .with_server(id, |server| server.port)
+        .ok_or("Server is not running")?;
+    let url = network::share_url(&ip, port);
+    app.clipboard().write_text(url.clone()).map_err(|e| e.to_string())?;
+    Ok(url)
+}
+
+#[derive(Serialize)]
+struct PublicAddress {
+    /// Public IPv4, `None` when offline or the STUN server didn't answer
+    ip: Option<String>,
+    /// Link for devices outside the LAN, set while a server has a UPnP
+    /// mapping so the port is actually reachable
+    external_url: Option<String>,
+}
+
+// Asks a STUN server (`host:port`) which address our traffic comes from
+#[tauri::command]
+async fn get_public_ip(
+    state: State<'_, ServiceState>,
+    endpoint: Option<String>,
+    id: Option<u64>,
+) -> Result<PublicAddress, String> {
+    let endpoint = endpoint.unwrap_or_else(|| network::DEFAULT_STUN_SERVER.to_string());
+    let cached = state
+        .public_ip
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|cached| cached.endpoint == endpoint && cached.fetched_at.elapsed() < PUBLIC_IP_CACHE_TTL)
+        .map(|cached| cached.ip.clone());
+    let ip = match cached {
+        Some(ip) => ip,
+        None => {
+            let ip = network::public_ip(&endpoint, PUBLIC_IP_TIMEOUT).await.map(|ip| ip.to_string());
+            *state.public_ip.lock().unwrap() = Some(CachedPublicIp {
+                endpoint,
+                ip: ip.clone(),
+                fetched_at: Instant::now(),
+            });
+            ip
+        }
+    };
+
+    let external_url = state
+        .with_server(id, |server| {
+            let mapped_ip = server.external_ip.as_ref()?;
+            Some(network::share_url(ip.as_ref().unwrap_or(mapped_ip), server.port))
+        })
+        .flatten();
+    Ok(PublicAddress { ip, external_url })
+}
+
+// Port tried first when sharing a file passed on the command line
+const LAUNCH_PORT: u16 = 8080;
+const LAUNCH_PORT_ATTEMPTS: u16 = 20;
+
+// Payload of the `launch-share` event sent once a path given at launch is shared
+#[derive(Clone, Serialize)]
+struct LaunchShare {
+    id: u64,
+    path: String,
+    urls: Vec<String>,
+}
+
+// The first argument that isn't a flag, as a plain path or a `file://` URL,
+// so "Share with HFS" entries in file managers can pass either
+fn launch_path() -> Option<PathBuf> {
+    let arg = std::env::args().skip(1).find(|arg| !arg.starts_with('-'))?;
+    match arg.strip_prefix("file://") {
+        Some(url_path) => Some(PathBuf::from(percent_decode(url_path)?)),
+        None => Some(PathBuf::from(arg)),
+    }
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
     }
+    String::from_utf8(bytes).ok()
+}
+
+// Shares `path` on its own as soon as the app starts. Problems are logged
+// and the app opens as usual.
+async fn share_launch_path(app: AppHandle, path: PathBuf) {
+    if !path.exists() {
+        log::error!("Not sharing {}: it does not exist", path.display());
+        return;
+    }
+    let path = path.canonicalize().unwrap_or(path).to_string_lossy().into_owned();
+    let Some(port) = (LAUNCH_PORT..LAUNCH_PORT + LAUNCH_PORT_ATTEMPTS)
+        .find(|&port| network::is_port_available(IpAddr::from(http::BIND_ADDRESS), port).unwrap_or(false))
+    else {
+        log::error!("Not sharing {}: no free port from {}", path, LAUNCH_PORT);
+        return;
+    };
+
+    let shared_folders = vec![http::SharedFolder::from(path.clone())];
+    let started = start_server_cmd(app.state(), port, shared_folders, None, None, None, None, None, app.clone()).await;
+    match started {
+        Ok(info) => {
+            let urls = network::get_local_ips().iter().map(|ip| network::share_url(ip, port)).collect();
+            log::info!("Sharing {} on port {}", path, port);
+            let _ = app.emit("launch-share", LaunchShare { id: info.id, path, urls });
+        }
+        Err(StartError::InvalidFolders { folders }) => {
+            for folder in folders {
+                log::error!("Not sharing {}: {}", folder.path, folder.reason);
+            }
+        }
+        Err(
+            StartError::InvalidDefaultPath { message }
+            | StartError::InvalidOptions { message }
+            | StartError::BindFailed { message },
+        ) => {
+            log::error!("Not sharing {}: {}", path, message);
+        }
+        Err(StartError::NoShares) => {
+            log::error!("Not sharing {}: nothing left to share", path);
+        }
+    }
+}
+
+// Accepts off, error, warn, info, debug or trace
+#[tauri::command]
+fn set_log_level(level: String) -> Result<(), String> {
+    let level: log::LevelFilter = level
+        .parse()
+        .map_err(|_| format!("Unknown log level: {}", level))?;
+    log::set_max_level(level);
+    log::info!("Log level set to {}", level);
     Ok(())
 }
 
+#[derive(Serialize)]
+struct LogFile {
+    path: String,
+    contents: String,
+}
+
+#[tauri::command]
+fn get_latest_log(app: AppHandle) -> Result<LogFile, String> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let path = app
+        .path()
+        .app_log_dir()
+        .map_err(|e| e.to_string())?
+        .join(format!("{}.log", LOG_FILE_NAME));
+    let mut file = std::fs::File::open(&path).map_err(|e| e.to_string())?;
+    let len = file.metadata().map_err(|e| e.to_string())?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(LOG_TAIL_BYTES)))
+        .map_err(|e| e.to_string())?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+    Ok(LogFile {
+        path: path.to_string_lossy().into_owned(),
+        contents: String::from_utf8_lossy(&bytes).into_owned(),
+    })
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_shell::init())
+        .plugin(tauri_plugin_clipboard_manager::init())
         .manage(ServiceState {
-            shutdown_tx: Mutex::new(None),
+            servers: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+            public_ip: Mutex::new(None),
         })
         .invoke_handler(tauri::generate_handler![
             get_local_ips,
+            is_port_available,
             start_server_cmd,
-            stop_server_cmd
+            stop_server_cmd,
+            stop_all_servers,
+            list_servers,
+            get_public_ip,
+            get_recent_downloads,
+            get_transfer_stats,
+            copy_share_url,
+            get_share_qr_codes,
+            reveal_in_explorer,
+            set_download_limit,
+            pick_shared_folders,
+            get_full_config,
+            export_config,
+            import_config,
+            set_log_level,
+            get_latest_log
         ])
         .setup(|app| {
+            let mut targets = vec![Target::new(TargetKind::LogDir {
+                file_name: Some(LOG_FILE_NAME.to_string()),
+            })];
             if cfg!(debug_assertions) {
-                app.handle().plugin(
-                    tauri_plugin_log::Builder::default()
-                        .level(log::LevelFilter::Info)
-                        .build(),
-                )?;
+                targets.push(Target::new(TargetKind::Stdout));
+            }
+            app.handle().plugin(
+                tauri_plugin_log::Builder::default()
+                    .clear_targets()
+                    .targets(targets)
+                    .max_file_size(LOG_MAX_BYTES)
+                    .rotation_strategy(RotationStrategy::KeepSome(LOG_KEEP_FILES))
+                    // Let everything through to the logger, set_log_level decides what is kept
+                    .level(log::LevelFilter::Trace)
+                    .build(),
+            )?;
+            log::set_max_level(log::LevelFilter::Info);
+            if let Some(path) = launch_path() {
+                tauri::async_runtime::spawn(share_launch_path(app.handle().clone(), path));
             }
             Ok(())
         })
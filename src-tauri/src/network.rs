@@ -1,19 +1,227 @@
-use local_ip_address::list_afinet_netifas;
-use std::net::IpAddr;
+use igd_next::aio::tokio::{search_gateway, Tokio};
+use igd_next::aio::Gateway;
+use igd_next::{PortMappingProtocol, SearchOptions};
+use local_ip_address::{list_afinet_netifas, local_ip};
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
 
+/// LAN addresses of this machine, the one guests are most likely to reach
+/// first. See `address_rank`.
 pub fn get_local_ips() -> Vec<String> {
     let mut ips = Vec::new();
     if let Ok(network_interfaces) = list_afinet_netifas() {
-        for (_, ip) in network_interfaces {
-            match ip {
-                IpAddr::V4(ipv4) => {
-                    if !ipv4.is_loopback() {
-                        ips.push(ipv4.to_string());
-                    }
+        for (name, ip) in network_interfaces {
+            // Ignore IPv6 for simplicity as per requirements
+            if let IpAddr::V4(ipv4) = ip {
+                if !ipv4.is_loopback() {
+                    ips.push((name, ipv4));
                 }
-                _ => {} // Ignore IPv6 for simplicity as per requirements
             }
         }
     }
-    ips
+    // Stable, so equally good addresses keep the interface order
+    ips.sort_by_key(|(name, ip)| address_rank(name, *ip));
+    ips.into_iter().map(|(_, ip)| ip.to_string()).collect()
+}
+
+// Interface names used by VPNs, hypervisors and containers, whose addresses
+// guests on the LAN can't reach
+const VIRTUAL_PREFIXES: &[&str] = &[
+    "docker", "veth", "br-", "virbr", "vmnet", "vboxnet", "tun", "tap", "utun", "wg", "zt", "awdl", "llw",
+];
+const VIRTUAL_WORDS: &[&str] = &[
+    "virtual", "vmware", "vpn", "tailscale", "zerotier", "hamachi", "wireguard", "hyper-v", "vethernet",
+];
+
+fn is_virtual_interface(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    VIRTUAL_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+        || VIRTUAL_WORDS.iter().any(|word| name.contains(word))
+}
+
+// Lower is better: private ranges on physical adapters first, then other
+// addresses, with virtual adapters after those and self-assigned
+// link-local addresses, which mean DHCP failed, last
+fn address_rank(interface: &str, ip: Ipv4Addr) -> (bool, bool, bool) {
+    (ip.is_link_local(), is_virtual_interface(interface), !ip.is_private())
+}
+
+/// The address a browser on the LAN should open to reach the server.
+pub fn share_url(ip: &str, port: u16) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ipv6)) => format!("http://[{}]:{}", ipv6, port),
+        _ => format!("http://{}:{}", ip, port),
+    }
+}
+
+/// Whether `port` can be bound on `addr`. The probe listener is closed before
+/// returning so the real server can take the port right after. Errors other
+/// than "in use", e.g. a privileged port, are passed on.
+pub fn is_port_available(addr: IpAddr, port: u16) -> std::io::Result<bool> {
+    match std::net::TcpListener::bind(SocketAddr::new(addr, port)) {
+        Ok(listener) => {
+            drop(listener);
+            Ok(true)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// An active UPnP port mapping on the local gateway.
+pub struct PortMapping {
+    gateway: Gateway<Tokio>,
+    port: u16,
+}
+
+impl PortMapping {
+    pub async fn remove(self) {
+        if let Err(e) = self.gateway.remove_port(PortMappingProtocol::TCP, self.port).await {
+            log::warn!("Failed to remove UPnP mapping for port {}: {}", self.port, e);
+        }
+    }
+}
+
+// Ask the gateway to forward `port` to this machine and return the mapping
+// together with the gateway's external IP.
+pub async fn add_port_mapping(port: u16) -> Result<(PortMapping, IpAddr), String> {
+    let gateway = search_gateway(SearchOptions::default())
+        .await
+        .map_err(|e| format!("No UPnP gateway found ({}). Forward port {} manually on your router.", e, port))?;
+
+    let local_ip = local_ip().map_err(|e| format!("Could not determine local IP: {}", e))?;
+    let local_addr = SocketAddr::new(local_ip, port);
+
+    gateway
+        .add_port(PortMappingProtocol::TCP, port, local_addr, 0, "HFS")
+        .await
+        .map_err(|e| format!("UPnP port mapping failed ({}). Forward port {} manually on your router.", e, port))?;
+
+    let mapping = PortMapping { gateway, port };
+    match mapping.gateway.get_external_ip().await {
+        Ok(ip) => Ok((mapping, ip)),
+        Err(e) => {
+            mapping.remove().await;
+            Err(format!("Could not read external IP from gateway: {}", e))
+        }
+    }
+}
+
+/// STUN server asked for the public address when no other is configured
+pub const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
+
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// The public IPv4 address this machine reaches the internet from, as seen
+/// by the STUN server at `server` (`host:port`). `None` when offline or the
+/// server doesn't answer within `timeout`.
+pub async fn public_ip(server: &str, timeout: Duration) -> Option<Ipv4Addr> {
+    match tokio::time::timeout(timeout, stun_binding(server)).await {
+        Ok(Ok(ip)) => Some(ip),
+        Ok(Err(e)) => {
+            log::debug!("STUN request to {} failed: {}", server, e);
+            None
+        }
+        Err(_) => {
+            log::debug!("STUN request to {} timed out", server);
+            None
+        }
+    }
+}
+
+async fn stun_binding(server: &str) -> std::io::Result<Ipv4Addr> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string());
+    let server = tokio::net::lookup_host(server)
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| invalid("STUN server has no IPv4 address"))?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(server).await?;
+
+    // Only needs to tell our request apart from stray packets
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    let transaction_id: [u8; 12] = {
+        let mut id = [0; 12];
+        id[..8].copy_from_slice(&hasher.finish().to_be_bytes());
+        id[8..].copy_from_slice(&std::process::id().to_be_bytes());
+        id
+    };
+
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+    socket.send(&request).await?;
+
+    let mut buf = [0u8; 512];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        let response = &buf[..len];
+        if len < 20
+            || response[0..2] != STUN_BINDING_RESPONSE.to_be_bytes()
+            || response[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+            || response[8..20] != transaction_id
+        {
+            continue;
+        }
+        return mapped_ipv4(&response[20..]).ok_or_else(|| invalid("STUN response has no IPv4 address"));
+    }
+}
+
+// Reads the mapped address from the attributes of a binding response,
+// preferring XOR-MAPPED-ADDRESS as RFC 5389 asks
+fn mapped_ipv4(mut attributes: &[u8]) -> Option<Ipv4Addr> {
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + len)?;
+        // Family 1 is IPv4: reserved byte, family, port, address
+        if value.len() == 8 && value[1] == 1 {
+            let address = u32::from_be_bytes([value[4], value[5], value[6], value[7]]);
+            match kind {
+                STUN_XOR_MAPPED_ADDRESS => return Some(Ipv4Addr::from(address ^ STUN_MAGIC_COOKIE)),
+                STUN_MAPPED_ADDRESS => mapped = Some(Ipv4Addr::from(address)),
+                _ => {}
+            }
+        }
+        // Attributes are padded to a multiple of 4 bytes
+        let padded = (4 + len).div_ceil(4) * 4;
+        attributes = attributes.get(padded..).unwrap_or_default();
+    }
+    mapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lan_addresses_on_physical_adapters_rank_first() {
+        let interfaces = [
+            ("docker0", [172, 17, 0, 1]),
+            ("Ethernet 2", [169, 254, 12, 7]),
+            ("tailscale0", [100, 101, 2, 3]),
+            ("utun3", [10, 8, 0, 2]),
+            ("eth0", [203, 0, 113, 9]),
+            ("vEthernet (WSL)", [172, 28, 160, 1]),
+            ("wlan0", [192, 168, 1, 20]),
+            ("en0", [10, 0, 0, 5]),
+        ];
+        let mut ips: Vec<(&str, Ipv4Addr)> = interfaces.iter().map(|(name, ip)| (*name, Ipv4Addr::from(*ip))).collect();
+        ips.sort_by_key(|(name, ip)| address_rank(name, *ip));
+        let names: Vec<&str> = ips.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            ["wlan0", "en0", "eth0", "docker0", "utun3", "vEthernet (WSL)", "tailscale0", "Ethernet 2"]
+        );
+    }
 }